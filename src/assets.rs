@@ -0,0 +1,77 @@
+use std::{error::Error, ffi::OsStr, fs, io, path::Path};
+
+use globset::{Glob, GlobSet, GlobSetBuilder};
+use simple_error::bail;
+
+/// Patterns of the source files which are never copied to the output directory
+pub const DEFAULT_IGNORE: [&str; 1] = [".*"];
+
+/// Decides which source files are static assets (stylesheets, scripts, images, fonts...) to be copied along with the rendered pages
+#[derive(Clone)]
+pub struct AssetFilter {
+    ignored: GlobSet,
+}
+
+impl AssetFilter {
+    /// Creates a filter ignoring the files matching any of the provided glob patterns in addition to the default ones.
+    /// Patterns are matched against the file path relative to the source directory as well as against the file name.
+    pub fn new(patterns: &[String]) -> Result<AssetFilter, Box<dyn Error>> {
+        let mut builder = GlobSetBuilder::new();
+        for pattern in DEFAULT_IGNORE.iter().map(|p| p.to_string()).chain(patterns.iter().cloned()) {
+            match Glob::new(&pattern) {
+                Ok(g) => {
+                    builder.add(g);
+                }
+                Err(e) => bail!("Invalid ignore pattern '{}' : {}", pattern, e),
+            }
+        }
+
+        match builder.build() {
+            Ok(ignored) => Ok(AssetFilter { ignored }),
+            Err(e) => bail!("Unable to build the assets ignore list : {}", e),
+        }
+    }
+
+    /// Returns true if the provided source file (relative to the source directory) is a static asset
+    pub fn is_asset(&self, relative: &Path) -> bool {
+        if relative.extension() == Some(OsStr::new("md")) {
+            return false;
+        }
+        let ignored = self.ignored.is_match(relative)
+            || relative.file_name().is_some_and(|n| self.ignored.is_match(n));
+        !ignored
+    }
+}
+
+/// Returns true if the destination of a source asset is missing or has a different content
+pub fn asset_changed(source: &Path, destination: &Path) -> io::Result<bool> {
+    if !destination.is_file() || fs::metadata(source)?.len() != fs::metadata(destination)?.len() {
+        return Ok(true);
+    }
+    Ok(fs::read(source)? != fs::read(destination)?)
+}
+
+/// Copies a source asset to its destination unless the destination already has the same content.
+/// Returns true if the asset was copied.
+pub fn copy_asset(source: &Path, destination: &Path) -> io::Result<bool> {
+    if !asset_changed(source, destination)? {
+        return Ok(false);
+    }
+
+    if let Some(parent) = destination.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    fs::copy(source, destination)?;
+    Ok(true)
+}
+
+/// Returns true if the provided file of the output directory was rendered from a markdown source
+pub fn is_rendered(output_file: &Path, root_path: &Path, output_path: &Path) -> bool {
+    match output_file.strip_prefix(output_path) {
+        Ok(relative) => {
+            relative.extension() == Some(OsStr::new("html"))
+                && root_path.join(relative).with_extension("md").is_file()
+        }
+        Err(_) => false,
+    }
+}
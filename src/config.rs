@@ -0,0 +1,300 @@
+use std::{
+    collections::BTreeMap,
+    env,
+    error::Error,
+    fs,
+    path::{Path, PathBuf},
+};
+
+use pulldown_cmark::Options;
+use serde::{Deserialize, Serialize};
+use log::warn;
+use simple_error::bail;
+
+use crate::assets::AssetFilter;
+use crate::convert::RenderSettings;
+use crate::watcher::WatchMode;
+
+/// Name of the configuration file looked up in the working directory
+pub const CONFIG_FILE: &str = "micro.toml";
+
+/// Name of the directory, next to the configuration file, where micro keeps track of what it published
+pub const STATE_DIRECTORY: &str = ".micro";
+
+/// Project configuration, usually read from a micro.toml file.
+/// Every setting has a default value so that micro can run without any configuration file.
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields, default)]
+pub struct Config {
+    pub site: Site,
+    pub paths: Paths,
+    pub server: Server,
+    pub markdown: Markdown,
+    pub features: Features,
+    pub assets: Assets,
+    pub lint: Lint,
+    pub watcher: Watcher,
+    pub build: Build,
+    /// Directory containing the configuration file (or the working directory if there is none)
+    #[serde(skip)]
+    pub project_path: PathBuf,
+}
+
+/// General information about the website, exposed to the templates as **site**.
+/// Any additional setting of the [site] table is exposed to the templates as well.
+#[derive(Debug, Default, Deserialize, Serialize)]
+#[serde(default)]
+pub struct Site {
+    pub title: Option<String>,
+    pub base_url: Option<String>,
+    pub author: Option<String>,
+    /// Image used when sharing the pages on social networks
+    pub image: Option<String>,
+    #[serde(flatten)]
+    pub extra: BTreeMap<String, toml::Value>,
+}
+
+/// Locations of the source, templates and output directories.
+/// Relative paths are resolved against the directory containing the configuration file.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields, default)]
+pub struct Paths {
+    pub source: PathBuf,
+    pub templates: PathBuf,
+    pub output: PathBuf,
+}
+
+impl Default for Paths {
+    fn default() -> Self {
+        Paths {
+            source: PathBuf::from("wwwroot"),
+            templates: PathBuf::from("templates"),
+            output: PathBuf::from("public"),
+        }
+    }
+}
+
+/// Development server settings
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields, default)]
+pub struct Server {
+    pub host: String,
+    pub port: u16,
+}
+
+impl Default for Server {
+    fn default() -> Self {
+        Server {
+            host: String::from("127.0.0.1"),
+            port: 4200,
+        }
+    }
+}
+
+/// Markdown extensions enabled when converting pages, all of them are enabled by default
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields, default)]
+pub struct Markdown {
+    pub tables: bool,
+    pub footnotes: bool,
+    pub strikethrough: bool,
+    pub tasklists: bool,
+    pub smart_punctuation: bool,
+}
+
+impl Default for Markdown {
+    fn default() -> Self {
+        Markdown {
+            tables: true,
+            footnotes: true,
+            strikethrough: true,
+            tasklists: true,
+            smart_punctuation: true,
+        }
+    }
+}
+
+impl Markdown {
+    /// Returns the markdown parser options matching the enabled extensions
+    pub fn options(&self) -> Options {
+        let mut options = Options::empty();
+        options.set(Options::ENABLE_TABLES, self.tables);
+        options.set(Options::ENABLE_FOOTNOTES, self.footnotes);
+        options.set(Options::ENABLE_STRIKETHROUGH, self.strikethrough);
+        options.set(Options::ENABLE_TASKLISTS, self.tasklists);
+        options.set(Options::ENABLE_SMART_PUNCTUATION, self.smart_punctuation);
+        options
+    }
+}
+
+/// Optional behaviours which can be turned off
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields, default)]
+pub struct Features {
+    /// Opens the default browser when starting the development server
+    pub open_browser: bool,
+    /// Copies the static assets found in the source directory to the output directory, the assets copied before are kept when turned off
+    pub copy_assets: bool,
+    /// Removes the files micro generated in the output directory which source does not exist anymore
+    pub remove_stale: bool,
+}
+
+impl Default for Features {
+    fn default() -> Self {
+        Features {
+            open_browser: true,
+            copy_assets: true,
+            remove_stale: true,
+        }
+    }
+}
+
+/// Static assets settings
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields, default)]
+pub struct Assets {
+    /// Glob patterns of the source files which should not be copied to the output directory
+    pub ignore: Vec<String>,
+}
+
+/// Metadata validation settings
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields, default)]
+pub struct Lint {
+    /// Path to the YAML file declaring the metadata schema
+    pub schema: PathBuf,
+}
+
+impl Default for Lint {
+    fn default() -> Self {
+        Lint {
+            schema: PathBuf::from("schema.yaml"),
+        }
+    }
+}
+
+/// File changes detection settings used in development mode
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields, default)]
+pub struct Watcher {
+    /// Rescans the directories periodically instead of relying on the operating system notifications,
+    /// useful on network drives or containers volumes where notifications are not delivered
+    pub poll: bool,
+    /// Interval in milliseconds between two scans when polling
+    pub poll_interval: u64,
+    /// Time in milliseconds without any change after which the pending changes are processed at once
+    pub debounce: u64,
+}
+
+impl Default for Watcher {
+    fn default() -> Self {
+        Watcher {
+            poll: false,
+            poll_interval: 1000,
+            debounce: 200,
+        }
+    }
+}
+
+impl Watcher {
+    /// Returns how file changes should be detected
+    pub fn mode(&self) -> WatchMode {
+        if self.poll {
+            WatchMode::Poll(self.poll_interval)
+        } else {
+            WatchMode::Native
+        }
+    }
+}
+
+/// Publishing settings
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields, default)]
+pub struct Build {
+    /// Number of pages rendered concurrently, 0 uses one thread per available core
+    pub jobs: usize,
+}
+
+impl Config {
+    /// Loads the configuration from the provided file, or from the micro.toml file of the working directory if there is one.
+    /// Defaults are used if no configuration file is provided nor found.
+    pub fn load(path: Option<&Path>) -> Result<Config, Box<dyn Error>> {
+        let cwd = env::current_dir()?;
+        let file = match path {
+            Some(p) => cwd.join(p),
+            None => {
+                let p = cwd.join(CONFIG_FILE);
+                if !p.is_file() {
+                    return Ok(Config::default().resolve(&cwd));
+                }
+                p
+            }
+        };
+
+        let content = match fs::read_to_string(&file) {
+            Ok(c) => c,
+            Err(e) => bail!("Unable to read the configuration file {:#?} : {}", file, e),
+        };
+
+        let config: Config = match toml::from_str(&content) {
+            Ok(c) => c,
+            Err(e) => bail!("Invalid configuration file {:#?} : {}", file, e),
+        };
+
+        let base = file.parent().map_or(cwd.clone(), Path::to_path_buf);
+        Ok(config.resolve(&base))
+    }
+
+    /// Makes the configured paths absolute, relative paths being resolved against the provided base directory
+    fn resolve(mut self, base: &Path) -> Config {
+        self.paths.source = base.join(&self.paths.source);
+        self.paths.templates = base.join(&self.paths.templates);
+        self.paths.output = base.join(&self.paths.output);
+        self.lint.schema = base.join(&self.lint.schema);
+        self.project_path = base.to_path_buf();
+        self
+    }
+
+    /// Returns the filter deciding which source files are copied to the output directory
+    pub fn asset_filter(&self) -> Result<AssetFilter, Box<dyn Error>> {
+        AssetFilter::new(&self.assets.ignore)
+    }
+
+    /// Makes sure that writing to the output directory cannot overwrite or remove the source files,
+    /// which would happen if the output directory was the source directory or one of its parents
+    pub fn check_output(&self) -> Result<(), Box<dyn Error>> {
+        // An output directory which does not exist yet cannot contain the source directory
+        let (source, output) = match (fs::canonicalize(&self.paths.source), fs::canonicalize(&self.paths.output)) {
+            (Ok(s), Ok(o)) => (s, o),
+            _ => return Ok(()),
+        };
+        if source.starts_with(&output) {
+            bail!(
+                "The output directory {:#?} contains the source directory {:#?}, please make sure the 'out' argument or the 'paths.output' setting points to a dedicated directory",
+                self.paths.output,
+                self.paths.source
+            );
+        }
+        Ok(())
+    }
+
+    /// Returns the directory where micro keeps track of what it published
+    pub fn state_path(&self) -> PathBuf {
+        self.project_path.join(STATE_DIRECTORY)
+    }
+
+    /// Returns the settings used to convert markdown files to html
+    pub fn render_settings(&self) -> RenderSettings {
+        RenderSettings {
+            templates_location: self.paths.templates.clone(),
+            markdown_options: self.markdown.options(),
+            site: match tera::to_value(&self.site) {
+                Ok(v) => v,
+                Err(e) => {
+                    warn!("Unable to expose the site settings to the templates : {}", e);
+                    tera::Value::Null
+                }
+            },
+        }
+    }
+}
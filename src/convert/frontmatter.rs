@@ -0,0 +1,276 @@
+use std::{error, fmt::Display};
+
+use serde_yaml::{Mapping, Value};
+use simple_error::bail;
+
+/// Line delimiting a YAML Meta Data block
+const YAML_DELIMITER: &str = "---";
+/// Line delimiting a TOML Meta Data block
+const TOML_DELIMITER: &str = "+++";
+
+/// Format of the Meta Data block found at the top of a markdown file
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    Yaml,
+    Toml,
+    Json,
+}
+
+impl Display for Format {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(
+            f,
+            "{}",
+            match self {
+                Format::Yaml => "YAML",
+                Format::Toml => "TOML",
+                Format::Json => "JSON",
+            }
+        )
+    }
+}
+
+/// Meta Data block found at the top of a markdown document.
+/// Whatever its original format, the Meta Data are normalized as a YAML value.
+pub struct FrontMatter {
+    pub format: Format,
+    pub value: Value,
+    /// Offset at which the document content starts
+    pub body_start: usize,
+}
+
+/// Error raised when a Meta Data block cannot be parsed
+#[derive(Debug, Clone)]
+pub struct MetadataError {
+    pub format: Format,
+    pub message: String,
+    /// Line and column of the error in the markdown document, starting at 1
+    pub location: Option<(usize, usize)>,
+}
+
+impl Display for MetadataError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self.location {
+            Some((line, column)) => write!(f, "Invalid {} metadata at line {}, column {}: {}", self.format, line, column, self.message),
+            None => write!(f, "Invalid {} metadata: {}", self.format, self.message),
+        }
+    }
+}
+
+impl error::Error for MetadataError {}
+
+fn invalid(format: Format, location: Option<(usize, usize)>, message: String) -> Box<dyn error::Error + Send + Sync> {
+    Box::new(MetadataError { format, message, location })
+}
+
+/// Locates and parses the Meta Data block leading a markdown document.
+/// * YAML blocks are delimited by lines consisting solely of "---"
+/// * TOML blocks are delimited by lines consisting solely of "+++"
+/// * JSON blocks consist of a single object starting at the very beginning of the document
+///
+/// Returns None if the document does not start with a Meta Data block and an error if the block cannot be parsed.
+pub fn parse(document: &str) -> Result<Option<FrontMatter>, Box<dyn error::Error + Send + Sync>> {
+    let bom = if document.starts_with('\u{feff}') { '\u{feff}'.len_utf8() } else { 0 };
+
+    if document[bom..].starts_with('{') {
+        return parse_json(document, bom).map(Some);
+    }
+
+    let opening = document[bom..].split_inclusive('\n').next().unwrap_or("");
+    let format = match opening.trim_end() {
+        YAML_DELIMITER => Format::Yaml,
+        TOML_DELIMITER => Format::Toml,
+        _ => return Ok(None),
+    };
+
+    let start = bom + opening.len();
+    let delimiter = opening.trim_end();
+    let mut offset = start;
+    for line in document[start..].split_inclusive('\n') {
+        if line.trim_end() == delimiter {
+            let text = &document[start..offset];
+            let value = match format {
+                Format::Yaml => parse_yaml(text)?,
+                _ => parse_toml(text)?,
+            };
+            return Ok(Some(FrontMatter {
+                format,
+                value,
+                body_start: offset + line.len(),
+            }));
+        }
+        offset += line.len();
+    }
+
+    bail!("Unterminated {} metadata block: missing closing \"{}\" line", format, delimiter)
+}
+
+fn parse_yaml(text: &str) -> Result<Value, Box<dyn error::Error + Send + Sync>> {
+    match serde_yaml::from_str(text) {
+        Ok(v) => Ok(v),
+        Err(e) => match e.location() {
+            // The Meta Data block starts right after the opening delimiter line
+            Some(l) => Err(invalid(Format::Yaml, Some((l.line() + 1, l.column())), strip_location(e.to_string()))),
+            None => Err(invalid(Format::Yaml, None, e.to_string())),
+        },
+    }
+}
+
+fn parse_toml(text: &str) -> Result<Value, Box<dyn error::Error + Send + Sync>> {
+    match toml::from_str::<toml::Table>(text) {
+        Ok(table) => Ok(toml_to_yaml(toml::Value::Table(table))),
+        Err(e) => match e.span() {
+            Some(span) => {
+                let before = &text[..span.start];
+                let line = before.matches('\n').count() + 1;
+                let column = before.len() - before.rfind('\n').map_or(0, |i| i + 1) + 1;
+                // The Meta Data block starts right after the opening delimiter line
+                Err(invalid(Format::Toml, Some((line + 1, column)), e.message().to_string()))
+            }
+            None => Err(invalid(Format::Toml, None, e.message().to_string())),
+        },
+    }
+}
+
+fn parse_json(document: &str, start: usize) -> Result<FrontMatter, Box<dyn error::Error + Send + Sync>> {
+    let mut stream = serde_json::Deserializer::from_str(&document[start..]).into_iter::<serde_json::Value>();
+    let json = match stream.next() {
+        Some(Ok(v)) => v,
+        Some(Err(e)) => return Err(invalid(Format::Json, Some((e.line(), e.column())), strip_location(e.to_string()))),
+        None => return Err(invalid(Format::Json, None, String::from("the document is empty"))),
+    };
+
+    if !json.is_object() {
+        return Err(invalid(Format::Json, None, String::from("expected an object")));
+    }
+
+    let value = serde_yaml::to_value(json)?;
+
+    // The content starts on the line following the closing brace
+    let end = start + stream.byte_offset();
+    let body_start = end + document[end..].find('\n').map_or(document.len() - end, |i| i + 1);
+
+    Ok(FrontMatter {
+        format: Format::Json,
+        value,
+        body_start,
+    })
+}
+
+/// Converts a TOML value to its YAML counterpart, dates being converted to their string representation
+fn toml_to_yaml(value: toml::Value) -> Value {
+    match value {
+        toml::Value::String(s) => Value::String(s),
+        toml::Value::Integer(i) => Value::from(i),
+        toml::Value::Float(f) => Value::from(f),
+        toml::Value::Boolean(b) => Value::Bool(b),
+        toml::Value::Datetime(d) => Value::String(d.to_string()),
+        toml::Value::Array(a) => Value::Sequence(a.into_iter().map(toml_to_yaml).collect()),
+        toml::Value::Table(t) => Value::Mapping(
+            t.into_iter()
+                .map(|(k, v)| (Value::String(k), toml_to_yaml(v)))
+                .collect::<Mapping>(),
+        ),
+    }
+}
+
+/// Drops the location appended to the parsers error messages as it is relative to the Meta Data block
+fn strip_location(message: String) -> String {
+    match message.rsplit_once(" at line ") {
+        Some((m, _)) => m.to_string(),
+        None => message,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn front_matter(document: &str) -> FrontMatter {
+        parse(document).unwrap().unwrap()
+    }
+
+    fn error(document: &str) -> MetadataError {
+        match parse(document) {
+            Err(e) => e.downcast_ref::<MetadataError>().unwrap().clone(),
+            Ok(_) => panic!("{:?} should not parse", document),
+        }
+    }
+
+    #[test]
+    fn yaml_block_only_leads_the_document() {
+        let document = "---\nlayout: article\n---\nBody\n\n---\n\n| a | b |\n|---|---|\n";
+        let parsed = front_matter(document);
+        assert_eq!(parsed.format, Format::Yaml);
+        assert_eq!(parsed.value["layout"].as_str(), Some("article"));
+        assert_eq!(&document[parsed.body_start..], "Body\n\n---\n\n| a | b |\n|---|---|\n");
+
+        assert!(parse("Intro\n---\nlayout: article\n---\n").unwrap().is_none());
+        assert!(parse("\n---\nlayout: article\n---\n").unwrap().is_none());
+    }
+
+    #[test]
+    fn unterminated_block_is_an_error() {
+        let message = parse("---\nlayout: article\n\nBody\n").err().unwrap().to_string();
+        assert_eq!(message, "Unterminated YAML metadata block: missing closing \"---\" line");
+    }
+
+    #[test]
+    fn bom_and_crlf_delimiters_are_supported() {
+        let document = "\u{feff}---\r\nlayout: article\r\ntitle: Title\r\n---\r\nBody\r\n";
+        let parsed = front_matter(document);
+        assert_eq!(parsed.value["title"].as_str(), Some("Title"));
+        assert_eq!(&document[parsed.body_start..], "Body\r\n");
+    }
+
+    #[test]
+    fn yaml_errors_are_located_in_the_document() {
+        let error = error("---\nlayout: article\ntitle: : nope\n---\n");
+        assert_eq!(error.format, Format::Yaml);
+        assert_eq!(error.location, Some((3, 8)));
+        assert!(!error.message.contains(" at line "));
+    }
+
+    #[test]
+    fn toml_block_is_normalized_as_yaml() {
+        let document = "+++\nlayout = \"article\"\ntags = [\"rust\"]\npublished-on = 2021-01-01T20:00:00Z\n+++\nBody\n";
+        let parsed = front_matter(document);
+        assert_eq!(parsed.format, Format::Toml);
+        assert_eq!(parsed.value["tags"][0].as_str(), Some("rust"));
+        assert_eq!(parsed.value["published-on"].as_str(), Some("2021-01-01T20:00:00Z"));
+        assert_eq!(&document[parsed.body_start..], "Body\n");
+    }
+
+    #[test]
+    fn toml_errors_are_located_in_the_document() {
+        let error = error("+++\nlayout = \"article\"\ntitle = nope\n+++\n");
+        assert_eq!(error.format, Format::Toml);
+        assert_eq!(error.location, Some((3, 9)));
+    }
+
+    #[test]
+    fn json_body_starts_after_the_object() {
+        let document = "{\n  \"layout\": \"article\",\n  \"nested\": {\"a\": \"}\"}\n}\nBody {not json}\n";
+        let parsed = front_matter(document);
+        assert_eq!(parsed.format, Format::Json);
+        assert_eq!(parsed.value["nested"]["a"].as_str(), Some("}"));
+        assert_eq!(&document[parsed.body_start..], "Body {not json}\n");
+
+        let parsed = front_matter("{\"layout\": \"article\"}");
+        assert_eq!(parsed.body_start, 21);
+    }
+
+    #[test]
+    fn json_errors_are_located_in_the_document() {
+        let error = error("{\n  \"layout\": \"article\"\n  \"title\": \"Title\"\n}\n");
+        assert_eq!(error.format, Format::Json);
+        assert_eq!(error.location, Some((3, 3)));
+        assert!(!error.message.contains(" at line "));
+    }
+
+    #[test]
+    fn location_is_stripped_from_messages() {
+        assert_eq!(strip_location(String::from("expected value at line 2 column 5")), "expected value");
+        assert_eq!(strip_location(String::from("expected value")), "expected value");
+    }
+}
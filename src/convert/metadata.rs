@@ -1,42 +1,58 @@
-use std::{fmt::Display, fs::File, path::Path, io::{prelude::*}};
+use std::{collections::BTreeMap, error, fmt::Display, fs::File, hash::{Hash, Hasher}, path::Path, io::{prelude::*}};
 
 use chrono::{DateTime, Utc};
-use log::{error, warn};
-use regex::Regex;
+use log::{error, trace};
 use serde_yaml::Value;
 
+use crate::convert::frontmatter;
+
 /// Template to apply to a markdown file during its rendering.
-#[derive(Debug, PartialEq, Eq, Hash)]
-pub enum Layout {
-    Article,
-    Index,
-    Undefined
+/// A layout is identified by the name of its template file (without the ".html" extension) in the templates directory.
+/// Layout names are case insensitive, "Article" and "article" designate the same layout.
+#[derive(Debug, Clone)]
+pub struct Layout(String);
+
+impl Layout {
+    /// Returns the name of the template backing this layout
+    pub fn name(&self) -> &str {
+        &self.0
+    }
+
+    /// Returns false if the layout name designates something else than a file of the templates directory
+    pub fn is_valid(&self) -> bool {
+        !self.0.is_empty() && !self.0.contains('/') && !self.0.contains('\\') && !self.0.contains("..")
+    }
+}
+
+impl PartialEq for Layout {
+    fn eq(&self, other: &Self) -> bool {
+        self.0.eq_ignore_ascii_case(&other.0)
+    }
+}
+
+impl Eq for Layout {}
+
+impl Hash for Layout {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.0.to_ascii_lowercase().hash(state);
+    }
 }
 
 impl Display for Layout {
     fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
-        write!(
-            f,
-            "{}",
-            match self {
-                Layout::Article => "Article",
-                Layout::Index => "Index",
-                Layout::Undefined => "Undefined",
-            }
-        )
+        write!(f, "{}", self.0)
     }
 }
 
 impl From<&str> for Layout {
     fn from(s: &str) -> Self {
-        match s.to_ascii_lowercase().as_str() {
-            "index" => Self::Index,
-            "article" => Self::Article,
-            _ => return Self::Undefined,
-        }
+        Layout(s.trim().to_string())
     }
 }
 
+/// Metadata fields handled by micro itself, any other field ends up in MarkdownMetaData::extra
+const KNOWN_FIELDS: [&str; 4] = ["layout", "title", "description", "published-on"];
+
 /// Optional metadata segment to be used in markdown files.
 pub struct MarkdownMetaData {
     pub layout: Layout,
@@ -44,86 +60,98 @@ pub struct MarkdownMetaData {
     pub description: Option<String>,
     pub source: Option<String>,
     pub published: DateTime<Utc>,
+    /// Custom fields defined by the page author, keyed by field name
+    pub extra: BTreeMap<String, Value>,
+    /// Problems which did not prevent reading the metadata (invalid publication date...), reported by the caller
+    pub warnings: Vec<String>,
 }
 
 impl Display for MarkdownMetaData {
     fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
         write!(
             f,
-            r#"Metadata{{Layout:"{:#?}", Title:"{:#?}", Description:"{:#?}", Published:"{}"}}"#,
-            self.layout, self.title, self.description, self.published
+            r#"Metadata{{Layout:"{:#?}", Title:"{:#?}", Description:"{:#?}", Published:"{}", Extra:"{:?}"}}"#,
+            self.layout, self.title, self.description, self.published, self.extra.keys()
         )
     }
 }
 
 impl MarkdownMetaData  {
     /// Extracts (and removes) the Meta Data from the markdown content.
-    pub fn extract(document: &mut String) -> Option<MarkdownMetaData> {
-        let re = Regex::new(r"(?s)---(.*?)---").unwrap();
-        if !re.is_match(&document) {
-            return None;
-        }
-
-        let metadata_text = match re.captures(&document){
-            Some(m) => m[1].to_string(), 
-            None => return None
+    /// Meta Data are only recognized as a leading block, either YAML delimited by "---" lines, TOML delimited by "+++" lines or a JSON object.
+    /// Returns None if the document has no Meta Data block and an error if the block cannot be parsed.
+    pub fn extract(document: &mut String) -> Result<Option<MarkdownMetaData>, Box<dyn error::Error + Send + Sync>> {
+        let front_matter = match frontmatter::parse(document)? {
+            Some(f) => f,
+            None => return Ok(None),
         };
 
-        let yaml: Value = match serde_yaml::from_str(&metadata_text) {
-            Ok(v) => v,
-            Err(_) => Value::default(),
-        };
+        trace!("Found {} metadata", front_matter.format);
+        let mdobj = MarkdownMetaData::from_yaml(front_matter.value);
 
-        let mdobj = MarkdownMetaData::from_yaml(yaml);
+        *document = document.split_off(front_matter.body_start);
 
-        *document = re.replace(document, "").to_string();
-
-        mdobj
+        Ok(mdobj)
     }
 
     /// Parses the provided YAML Meta Data content into a MarkdownMetaData structure.
+    /// TOML and JSON Meta Data are normalized as YAML beforehand.
+    /// Fields which are not handled by micro are kept as is in the **extra** map.
+    /// Returns None if the mandatory layout field is missing, the problems found in the other fields are listed in **warnings**.
     pub fn from_yaml(yaml: serde_yaml::Value) -> Option<MarkdownMetaData> {
-        Some(MarkdownMetaData {
-            layout: match yaml["layout"].as_str() {
-                Some(s) => Layout::from(s),
-                _ => {
-                    // Unlikely to happen
-                    error!(r#"Missing mandatory metadata field "layout" "#);
-                    return None;
-                }
-            },
-            title: match yaml["title"].as_str() {
-                Some(s) => Some(String::from(s)),
-                _ => None,
-            },
-            description: match yaml["description"].as_str() {
-                Some(s) => Some(s.to_string()),
-                _ => None,
-            },
-            source: None,
-            published: match yaml["published-on"].as_str() {
-                Some(s) => match DateTime::parse_from_rfc3339(s) {
-                    Ok(d) => DateTime::from(d),
-                    Err(e) => {
-                        warn!(
-                            "Unable to parse publication date {}: {} ...defaulting to today",
-                            s, e
-                        );
-                        Utc::now()
-                    }
-                },
-                _ => {
-                    warn!(r#"Missing metadata field "published-on ...defaulting to today" "#);
+        let layout = Layout::from(yaml["layout"].as_str()?);
+        let mut warnings = vec![];
+        let published = match yaml["published-on"].as_str() {
+            Some(s) => match DateTime::parse_from_rfc3339(s) {
+                Ok(d) => DateTime::from(d),
+                Err(e) => {
+                    warnings.push(format!("Unable to parse publication date {}: {} ...defaulting to today", s, e));
                     Utc::now()
                 }
             },
+            _ => {
+                warnings.push(String::from(r#"Missing metadata field "published-on" ...defaulting to today"#));
+                Utc::now()
+            }
+        };
+        let extra = match yaml.as_mapping() {
+            Some(mapping) => mapping
+                .iter()
+                .filter_map(|(k, v)| {
+                    let key = match k {
+                        Value::String(s) => s.clone(),
+                        Value::Number(n) => n.to_string(),
+                        Value::Bool(b) => b.to_string(),
+                        _ => {
+                            warnings.push(format!("Unsupported metadata field name {:?} ...skipping", k));
+                            return None;
+                        }
+                    };
+                    if KNOWN_FIELDS.contains(&key.as_str()) {
+                        None
+                    } else {
+                        Some((key, v.clone()))
+                    }
+                })
+                .collect(),
+            None => BTreeMap::new(),
+        };
+
+        Some(MarkdownMetaData {
+            layout,
+            title: yaml["title"].as_str().map(String::from),
+            description: yaml["description"].as_str().map(String::from),
+            source: None,
+            published,
+            extra,
+            warnings,
         })
     }
 
     // Gets the markdown metadata from the given file
     pub fn from_file(source:&Path) -> Option<MarkdownMetaData>{
         let mut content = Vec::new();
-        let mut source_file = match File::open(&source) {
+        let mut source_file = match File::open(source) {
             Ok(handle) => handle,
             Err(error) => {
                 error!("The following error occurred while opening {:#?} : {:#?}", error, &source);
@@ -146,6 +174,13 @@ impl MarkdownMetaData  {
             }
         };
 
-        MarkdownMetaData::extract(&mut utf8_content)
+        match MarkdownMetaData::extract(&mut utf8_content) {
+            Ok(metadata) => metadata,
+            Err(error) => {
+                error!("Unable to read the metadata of {:#?} : {}", &source, error);
+                None
+            }
+        }
     }
-}
\ No newline at end of file
+}
+
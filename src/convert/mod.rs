@@ -1,31 +1,59 @@
+pub mod frontmatter;
 pub mod metadata;
 pub mod template;
 
 use core::panic;
-use log::{debug, error};
+use log::{error, warn};
+use frontmatter::MetadataError;
 use metadata::MarkdownMetaData;
 use pulldown_cmark::{html, Options, Parser};
 use simple_error::bail;
-use std::{error, ffi::OsStr};
+use std::{error, ffi::OsStr, fmt::Display};
 use std::{
-    fs::File,
+    fs::{self, File},
     io::{prelude::*, BufWriter},
-    path::PathBuf,
-    str,
+    path::{Path, PathBuf},
 };
+use tera::Tera;
 use tokio::sync::mpsc::Receiver;
 
-use template::merge_template;
+use template::{merge_template, TemplateError};
+
+use crate::filesystem::hash;
+
+/// Settings driving the conversion of markdown files to html
+#[derive(Debug, Clone)]
+pub struct RenderSettings {
+    /// Directory where the layout templates are located
+    pub templates_location: PathBuf,
+    /// Markdown extensions to enable
+    pub markdown_options: Options,
+    /// Site wide data exposed to every template
+    pub site: tera::Value,
+}
+
+impl RenderSettings {
+    /// Returns a hash of the settings affecting every rendered page (site data and markdown extensions)
+    pub fn fingerprint(&self) -> String {
+        let site = serde_json::to_string(&self.site).unwrap_or_default();
+        hash(format!("{}|{}", self.markdown_options.bits(), site).as_bytes())
+    }
+}
 
 // Unused for now, leave it here for documentation
 pub async fn _create_markdown_pipeline(
     mut receiver: Receiver<PathBuf>,
-    templates_location: PathBuf,
+    settings: RenderSettings,
 ) -> tokio::task::JoinHandle<()> {
-    if !templates_location.exists() {
-        panic!(format!("Cannot initialize markdown pipeline : templates location {:#?} leads to a non existing path", &templates_location).as_str());
+    if !settings.templates_location.exists() {
+        panic!("Cannot initialize markdown pipeline : templates location {:#?} leads to a non existing path", &settings.templates_location);
     }
 
+    let engine = match template::load_templates(Some(settings.templates_location.clone())) {
+        Ok(e) => e,
+        Err(e) => panic!("Cannot initialize markdown pipeline : {}", e),
+    };
+
     tokio::task::spawn(async move {
         loop {
             let source = match receiver.recv().await {
@@ -33,85 +61,36 @@ pub async fn _create_markdown_pipeline(
                 None => continue,
             };
 
-            match markdown_to_html(source,None, templates_location.clone()){
-                Ok(_) => {}
+            let document = match render_markdown(&source, &settings, &engine) {
+                Ok(d) => d,
                 Err(e) => {
                     error!("{}", e);
                     continue;
                 }
+            };
+            if let Err(e) = write_html(&source, None, &document) {
+                error!("{}", e);
             }
         }
     })
 }
 
-/// Converts the source markdown file (which needs to have a .md file extension) to HTML using a layout template specified via Metadata 
-/// If no destination is specified, the generated html file will be placed in the same path as the source file with the ".html" extension
-pub fn markdown_to_html(source: PathBuf, destination: Option<PathBuf>, templates_location: PathBuf) 
--> Result<PathBuf, Box<dyn error::Error + Send + Sync>> 
-{
-    debug!("Publishing {:#?}", &source);
-
-    if source.extension() != Some(OsStr::new("md")) {
-        bail!("{:#?} is not markdown...skipping", source);
-    }
-
-    let mut markdown = Vec::new();
-    let mut source_file = match File::open(&source) {
-        Ok(handle) => handle,
-        Err(error) => {
-            bail!("The following error occurred while opening {:#?} : {:#?}", error, &source);
-        }
-    };
-    match source_file.read_to_end(&mut markdown) {
-        Ok(_) => {}
-        Err(error) => {
-            bail!("The following error occurred while reading {:#?} : {:#?}", error, &source);
-        }
-    };
-
-    let mut markdown_content = match String::from_utf8(markdown) {
-        Ok(m) => m,
-        Err(error) => {
-            bail!("UTF8 conversion error occured for {:#?} ...skipping : {:#?}", &source, error);
-        }
-    };
-
-    let metadata = match MarkdownMetaData::extract(&mut markdown_content) {
-        Some(meta) => meta,
-        None => {
-            bail!(
-                "Unable to extract metadata from {:#?}",
-                &source
-            );
-        }
-    };
-
-    // TODO : improve that, and keep a reference instead of re-instanciating it every time
-    let parser = Parser::new_ext(markdown_content.as_str(), Options::all());
-
-    let mut html = String::new();
-    html::push_html(&mut html, parser);
-
-    let mut template = Vec::new();
-    match template::load_template(&metadata.layout.to_string(), Some(templates_location), &mut template) {
-        Ok(_) => {}
-        Err(_) => {
-            bail!(
-                "Unable to load template [{:#?}] for {:#?}",
-                metadata.layout, &source
-            );
-        }
-    };
-
-    let content = str::from_utf8(&template).unwrap_or("");
- 
-
+/// Writes the html __document__ rendered from the __source__ markdown file to __destination__, or next to the source file if no destination is specified.
+/// Returns the path of the generated html file
+pub fn write_html(source: &Path, destination: Option<PathBuf>, document: &str) -> Result<PathBuf, Box<dyn error::Error + Send + Sync>> {
     let target = match destination {
         Some(p) => p.with_extension("html"),
         None => source.with_extension("html")
     };
 
-    let document = merge_template(content, &metadata, &html);
+    if let Some(parent) = target.parent() {
+        if let Err(e) = fs::create_dir_all(parent) {
+            bail!(
+                "Unable to create the destination directory {:#?} : {:#?}",
+                parent, e
+            );
+        }
+    }
 
     let html_file = match File::create(&target) {
         Ok(handle) => handle,
@@ -125,7 +104,7 @@ pub fn markdown_to_html(source: PathBuf, destination: Option<PathBuf>, templates
 
     let mut writer = BufWriter::new(html_file);
 
-    match writer.write(document.as_bytes()) {
+    match writer.write_all(document.as_bytes()) {
         Ok(_) => {}
         Err(e) => {
             bail!(
@@ -135,5 +114,129 @@ pub fn markdown_to_html(source: PathBuf, destination: Option<PathBuf>, templates
         }
     };
 
-    Ok(source.with_extension("html"))
+    Ok(target)
+}
+
+/// Error raised when a page cannot be rendered, locating the problem in its source file when possible
+#[derive(Debug, Clone)]
+pub struct RenderError {
+    pub file: PathBuf,
+    pub message: String,
+    /// Line and column of the error in the source file, starting at 1
+    pub location: Option<(usize, usize)>,
+    /// Template the error originates from, relative to the templates directory
+    pub template: Option<String>,
+    /// Line of the error in the template, starting at 1
+    pub template_line: Option<usize>,
+}
+
+impl RenderError {
+    pub fn new(file: &Path, message: String) -> RenderError {
+        RenderError {
+            file: file.to_path_buf(),
+            message,
+            location: None,
+            template: None,
+            template_line: None,
+        }
+    }
+
+    /// Creates an error located in the source file or in the templates according to its __cause__
+    pub fn with_cause(file: &Path, message: String, cause: &(dyn error::Error + 'static)) -> RenderError {
+        let mut error = RenderError::new(file, message);
+        if let Some(metadata) = cause.downcast_ref::<MetadataError>() {
+            error.location = metadata.location;
+        }
+        if let Some(template) = cause.downcast_ref::<TemplateError>() {
+            error.template = template.template.clone();
+            error.template_line = template.line;
+        }
+        error
+    }
+}
+
+impl Display for RenderError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{}", self.message)
+    }
+}
+
+impl error::Error for RenderError {}
+
+/// A markdown page split into its metadata and its content
+pub struct MarkdownPage {
+    pub metadata: MarkdownMetaData,
+    /// Markdown content following the metadata block
+    pub body: String,
+}
+
+/// Renders the source markdown file (which needs to have a .md file extension) to an HTML document using the layout template specified via Metadata.
+/// The templates are loaded once beforehand into __engine__ (see template::load_templates) and shared by all the pages.
+/// The problems found in the metadata which do not prevent the rendering are logged.
+pub fn render_markdown(source: &Path, settings: &RenderSettings, engine: &Tera) -> Result<String, RenderError> {
+    let page = parse_markdown(source, &read_markdown(source)?)?;
+    for warning in &page.metadata.warnings {
+        warn!("{:#?}: {}", source, warning);
+    }
+    render_page(source, &page, settings, engine)
+}
+
+/// Reads the content of the source markdown file (which needs to have a .md file extension)
+pub fn read_markdown(source: &Path) -> Result<String, RenderError> {
+    if source.extension() != Some(OsStr::new("md")) {
+        return Err(RenderError::new(source, format!("{:#?} is not markdown...skipping", source)));
+    }
+
+    let mut markdown = Vec::new();
+    let mut source_file = match File::open(source) {
+        Ok(handle) => handle,
+        Err(error) => {
+            return Err(RenderError::new(source, format!("The following error occurred while opening {:#?} : {:#?}", error, &source)));
+        }
+    };
+    match source_file.read_to_end(&mut markdown) {
+        Ok(_) => {}
+        Err(error) => {
+            return Err(RenderError::new(source, format!("The following error occurred while reading {:#?} : {:#?}", error, &source)));
+        }
+    };
+
+    match String::from_utf8(markdown) {
+        Ok(m) => Ok(m),
+        Err(error) => Err(RenderError::new(source, format!("UTF8 conversion error occured for {:#?} ...skipping : {:#?}", &source, error))),
+    }
+}
+
+/// Extracts the metadata from the __content__ of the source markdown file
+pub fn parse_markdown(source: &Path, content: &str) -> Result<MarkdownPage, RenderError> {
+    let mut body = content.to_string();
+    match MarkdownMetaData::extract(&mut body) {
+        Ok(Some(metadata)) => Ok(MarkdownPage { metadata, body }),
+        Err(e) => Err(RenderError::with_cause(
+            source,
+            format!("Unable to extract metadata from {:#?} : {}", &source, e),
+            e.as_ref(),
+        )),
+        Ok(None) => Err(RenderError::new(
+            source,
+            format!(r#"Unable to extract metadata from {:#?} : the file has to start with a metadata block declaring its "layout""#, &source),
+        )),
+    }
+}
+
+/// Renders a markdown page to an HTML document using the layout template specified via its metadata
+pub fn render_page(source: &Path, page: &MarkdownPage, settings: &RenderSettings, engine: &Tera) -> Result<String, RenderError> {
+    let parser = Parser::new_ext(page.body.as_str(), settings.markdown_options);
+
+    let mut html = String::new();
+    html::push_html(&mut html, parser);
+
+    match merge_template(engine, &settings.templates_location, &page.metadata, &html, &settings.site) {
+        Ok(d) => Ok(d),
+        Err(e) => Err(RenderError::with_cause(
+            source,
+            format!("Unable to render template [{}] for {:#?} : {}", page.metadata.layout, source, e),
+            e.as_ref(),
+        )),
+    }
 }
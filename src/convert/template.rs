@@ -1,60 +1,216 @@
-use std::{collections::HashMap, env, error, fs::{self, DirEntry, File}, io::{Read}, path::{Path, PathBuf}, time::SystemTime};
+use std::{collections::{BTreeMap, HashMap}, env, error::{self, Error}, ffi::OsStr, fmt::Display, fs, path::{Path, PathBuf, MAIN_SEPARATOR}};
 
 use log::{trace, warn};
+use regex::Regex;
 use simple_error::bail;
+use tera::{Context, Map, Tera, Value};
 use crate::convert::{MarkdownMetaData, metadata::Layout};
+use crate::filesystem::hash_file;
+
+/// Error raised when a template cannot be parsed or rendered, locating the problem in the templates when possible
+#[derive(Debug, Clone)]
+pub struct TemplateError {
+    pub message: String,
+    /// Name of the faulty template, relative to the templates directory
+    pub template: Option<String>,
+    /// Line of the error in the template, starting at 1
+    pub line: Option<usize>,
+}
+
+impl Display for TemplateError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{}", self.message)
+    }
+}
+
+impl error::Error for TemplateError {}
+
+/// Loads all the HTML templates found in the templates directory (or the "templates" folder of the current directory) into a template engine.
+/// Templates are registered under their path relative to the templates directory, a layout named "article" being registered as "article.html".
+pub fn load_templates(templates_root: Option<PathBuf>) -> Result<Tera, Box<dyn error::Error+Sync+Send>> {
 
-/// Loads an HTML template with the given name (without file extension) and returns its contents
-pub fn load_template(name: &str, templates_root: Option<PathBuf>, buffer: &mut Vec<u8>) -> Result<usize, Box<dyn error::Error+Sync+Send>> {
-   
     let cwd = match templates_root {
-        Some(path) => path, 
+        Some(path) => path,
         None => env::current_dir()?.join("templates")
     };
 
-    let template_path = cwd.join(name).with_extension("html");
-    trace!("Loading template {} from {:#?}", &name, &template_path);
+    let pattern = cwd.join("**").join("*.html");
+    trace!("Loading templates from {:#?}", &pattern);
 
-    let mut template_file = match File::open(&template_path) {
-        Ok(handle) => handle,
-        Err(error) => bail!(
-            "An error occured while trying to load the template {:#?} : {}",
-            &template_path,
-            error
+    match Tera::new(&pattern.to_string_lossy()) {
+        Ok(t) => Ok(t),
+        Err(e) => {
+            let mut error = locate_error(&e, &cwd);
+            error.message = format!("An error occured while loading the templates from {:#?} : {}", &cwd, error.message);
+            Err(Box::new(error))
+        }
+    }
+}
+
+/// Renders the template backing the provided layout using the page metadata and its html content.
+///
+/// Templates are written using the Tera syntax (https://tera.netlify.app/docs/#templates) and can access
+/// * **content** : the html generated from the markdown file, to be inserted using the "safe" filter as html templates are escaped automatically
+/// * **site** : the site wide settings (**site.title**, **site.base_url**, **site.author**, **site.image**...)
+/// * **page** : the page metadata (**page.title**, **page.description**, **page.published**, **page.layout**, **page.source**)
+///   along with any custom field defined in the page metadata (**page.author**, **page.cover.alt**...)
+pub fn merge_template(engine: &Tera, templates_path: &Path, metadata: &MarkdownMetaData, html_content: &str, site: &Value) -> Result<String, Box<dyn error::Error+Sync+Send>> {
+    if !metadata.layout.is_valid() {
+        bail!(
+            "Invalid layout '{}': layouts are named after the templates of {:#?} and cannot contain '/' or '..'",
+            &metadata.layout,
+            templates_path
+        );
+    }
+
+    let name = template_name(&metadata.layout);
+    let name = match engine.get_template_names().find(|n| n.eq_ignore_ascii_case(&name)) {
+        Some(n) => n.to_string(),
+        None => bail!(
+            "Unknown layout '{}': no template named \"{}\" in {:#?}. Available layouts are: {}",
+            &metadata.layout,
+            &name,
+            templates_path,
+            available_layouts(templates_path).join(", ")
         ),
     };
 
-    let bytes = match template_file.read_to_end(buffer) {
-        Ok(b) => b,
-        Err(error) => bail!(
-            "An error occured loading a template from {:#?} : {}",
-            template_path,
-            error
-        ),
+    let mut context = Context::new();
+    context.insert("content", html_content);
+    context.insert("page", &page_context(metadata));
+    context.insert("site", site);
+
+    match engine.render(&name, &context) {
+        Ok(document) => Ok(document),
+        Err(e) => Err(Box::new(locate_error(&e, templates_path))),
+    }
+}
+
+/// Returns the name of the template backing the provided layout
+pub fn template_name(layout: &Layout) -> String {
+    format!("{}.html", layout.name())
+}
+
+/// Builds the **page** object exposed to the templates from the markdown metadata
+fn page_context(metadata: &MarkdownMetaData) -> Value {
+    let mut page = Map::new();
+    page.insert("layout".to_string(), Value::from(metadata.layout.name()));
+    page.insert("title".to_string(), metadata.title.as_deref().map_or(Value::Null, Value::from));
+    page.insert("description".to_string(), metadata.description.as_deref().map_or(Value::Null, Value::from));
+    page.insert("source".to_string(), metadata.source.as_deref().map_or(Value::Null, Value::from));
+    page.insert("published".to_string(), Value::from(metadata.published.to_rfc3339()));
+    for (key, value) in &metadata.extra {
+        match tera::to_value(value) {
+            Ok(v) => {
+                page.insert(key.clone(), v);
+            }
+            Err(e) => warn!("Unable to expose the metadata field '{}' to the templates : {}", key, e),
+        }
+    }
+    Value::Object(page)
+}
+
+/// Flattens a template engine error and its causes into a single message.
+/// Parsing errors causes carry the template file name and the line/column of the faulty statement.
+fn describe_error(e: &tera::Error) -> String {
+    let mut message = e.to_string();
+    let mut cause = e.source();
+    while let Some(c) = cause {
+        message.push_str(&format!("\n{}", c));
+        cause = c.source();
+    }
+    message
+}
+
+/// Describes a template engine error, looking for the template and the line it originates from.
+/// Parsing errors carry the line of the faulty statement, rendering errors only name the template and the missing variable, filter or template
+/// which is then looked up in the template and the ones it depends on.
+fn locate_error(e: &tera::Error, templates_path: &Path) -> TemplateError {
+    let message = describe_error(e);
+    let innermost = message.lines().rfind(|l| !l.trim().is_empty()).unwrap_or("");
+
+    let parsed = Regex::new(r#"Failed to parse "([^"]+)""#).unwrap();
+    if let Some(capture) = parsed.captures(&message) {
+        let path = Path::new(&capture[1]);
+        let template = path.strip_prefix(templates_path).unwrap_or(path).to_string_lossy().replace(MAIN_SEPARATOR, "/");
+        let line = Regex::new(r"--> (\d+):\d+").unwrap().captures(&message).and_then(|c| c[1].parse().ok());
+        return TemplateError { message, template: Some(template), line };
+    }
+
+    let rendered = Regex::new(r"(?:while rendering|Failed to render) '([^']+)'").unwrap();
+    let template = match rendered.captures_iter(&message).last() {
+        Some(c) => c[1].to_string(),
+        None => return TemplateError { message, template: None, line: None },
     };
+    let needle = Regex::new(r"[`']\[?([^`'\]]+)\]?[`']").unwrap().captures(innermost).map(|c| c[1].to_string());
+    let found = needle.and_then(|needle| {
+        std::iter::once(template.clone())
+            .chain(dependencies(templates_path, &template))
+            .find_map(|name| {
+                let content = fs::read_to_string(templates_path.join(&name)).ok()?;
+                let line = content.lines().position(|l| l.contains(needle.as_str()))?;
+                Some((name, line + 1))
+            })
+    });
 
-    Ok(bytes)
+    match found {
+        Some((template, line)) => TemplateError { message, template: Some(template), line: Some(line) },
+        None => TemplateError { message, template: Some(template), line: None },
+    }
 }
 
-/// Merges a template with the provided metadata
-pub fn merge_template(template: &str, metadata: &MarkdownMetaData, html_content: &str) -> String {
-    let document = template
-        .replace("{{content}}", html_content)
-        .replace("{{title}}", &metadata.title.as_ref().unwrap_or(&"".to_string()))
-        .replace("{{description}}", &metadata.description.as_ref().unwrap_or(&"".to_string()))
-        .replace("{{published-on}}", &metadata.published.to_string())
-        .replace("{{source}}", &metadata.source.as_ref().unwrap_or(&"".to_string()));
-    document
+/// Lists the names of the layouts (html templates) available in the provided templates directory.
+/// Templates extended by other templates (base.html) are not meant to be used as a page layout and are left out.
+pub fn available_layouts(templates_path: &Path) -> Vec<String> {
+    let templates = layout_templates(templates_path);
+    let re = Regex::new(r#"\{%-?\s*extends\s+["']([^"']+)["']"#).unwrap();
+    let extended: Vec<String> = templates
+        .iter()
+        .filter_map(|name| fs::read_to_string(templates_path.join(format!("{}.html", name))).ok())
+        .flat_map(|content| re.captures_iter(&content).map(|c| c[1].to_string()).collect::<Vec<_>>())
+        .collect();
+
+    templates
+        .into_iter()
+        .filter(|name| !extended.contains(&format!("{}.html", name)))
+        .collect()
+}
+
+/// Lists the names of all the html templates located at the root of the provided templates directory, whether they are meant to be extended or not
+fn layout_templates(templates_path: &Path) -> Vec<String> {
+    let mut layouts: Vec<String> = match fs::read_dir(templates_path) {
+        Ok(entries) => entries
+            .filter_map(Result::ok)
+            .map(|entry| entry.path())
+            .filter(|path| path.is_file() && path.extension() == Some(OsStr::new("html")))
+            .filter_map(|path| path.file_stem().and_then(OsStr::to_str).map(String::from))
+            .collect(),
+        Err(e) => {
+            warn!("Unable to list the layouts available in '{:#?}': {}", templates_path, e);
+            vec![]
+        }
+    };
+    layouts.sort();
+    layouts
+}
+
+/// Given a template file find all the pages within the provided path rendered using it,
+/// either because it is their layout or because their layout extends or includes it
+pub fn find_usage(root_path: &Path, templates_path: &Path, template_file: &Path, matches: &mut Vec<PathBuf>){
+    let layouts = dependent_layouts(templates_path, template_file);
+    if !layouts.is_empty() {
+        find_layouts_usage(root_path, &layouts, matches);
+    }
 }
 
-/// Given a Layout find all the usages within the provided path
-pub fn find_usage(root_path: &Path, layout: &Layout, matches: &mut Vec<PathBuf>){
+/// Given a list of Layouts find all their usages within the provided path
+fn find_layouts_usage(root_path: &Path, layouts: &[Layout], matches: &mut Vec<PathBuf>){
 
-    let directory = match std::fs::read_dir(&root_path) { 
+    let directory = match std::fs::read_dir(root_path) { 
         Ok(d) => d,
         Err(e) => {
             warn!("Unable to read '{:#?}' content, the directory will be skipped : {}", &root_path, e);
-            return ();
+            return;
         }
     };
 
@@ -79,40 +235,98 @@ pub fn find_usage(root_path: &Path, layout: &Layout, matches: &mut Vec<PathBuf>)
         };
 
         if metadata.is_file() {
+            if item_path.extension() != Some(OsStr::new("md")) {
+                continue;
+            }
             if let Some(metadata) = MarkdownMetaData::from_file(&item_path){
-                if metadata.layout == *layout {
+                if layouts.contains(&metadata.layout) {
                     matches.push(item_path);
                 }
             }
         } else  {
-            find_usage(&item_path, layout, matches);
+            find_layouts_usage(&item_path, layouts, matches);
         }
     }
 }
 
-/// Parses the templates from the provided path and returns their last change timestamp
-pub fn last_changed(templates_path: &Path)->Result<HashMap<Layout, SystemTime>, Box<dyn error::Error>> {
-    let templates = match fs::read_dir(&templates_path) {
-        Ok(t) => {
-            let (success, _): (Vec<_>, Vec<_>) = t.partition(Result::is_ok);
-            success
-                .into_iter()
-                .map(Result::unwrap)
-                .collect::<Vec<DirEntry>>()
+/// Parses the templates from the provided path and returns, keyed by layout name, the hashes of the templates each layout is rendered with.
+/// Hashes are keyed by template name and account for the templates a layout depends on (base layouts and partials) so that changing any of them outdates the layout.
+pub fn fingerprints(templates_path: &Path) -> Result<HashMap<Layout, BTreeMap<String, String>>, Box<dyn error::Error>> {
+    if let Err(e) = fs::read_dir(templates_path) {
+        bail!("Unable to access templates path '{:#?}': {}", &templates_path, e)
+    }
+
+    let mut templates_registry: HashMap<Layout, BTreeMap<String, String>> = HashMap::new();
+    for name in layout_templates(templates_path) {
+        let layout = Layout::from(name.as_str());
+        let template = template_name(&layout);
+
+        let dependencies = dependencies(templates_path, &template);
+        let mut hashes = BTreeMap::new();
+        for dependency in std::iter::once(template).chain(dependencies) {
+            match hash_file(&templates_path.join(&dependency)) {
+                Ok(h) => {
+                    hashes.insert(dependency, h);
+                }
+                Err(e) => warn!("Unable to read the template {:#?} : {}", templates_path.join(&dependency), e),
+            }
         }
+        templates_registry.insert(layout, hashes);
+    }
+
+    Ok(templates_registry)
+}
+
+/// Returns the names (relative to the templates directory) of all the templates the provided template extends or includes, either directly or through other templates
+pub fn dependencies(templates_path: &Path, name: &str) -> Vec<String> {
+    let mut found: Vec<String> = vec![];
+    collect_dependencies(templates_path, name, &mut found);
+    found
+}
+
+fn collect_dependencies(templates_path: &Path, name: &str, found: &mut Vec<String>) {
+    let content = match fs::read_to_string(templates_path.join(name)) {
+        Ok(c) => c,
         Err(e) => {
-            bail!("Unable to access templates path '{:#?}': {}", &templates_path, e)
+            warn!("Unable to read the template {:#?} while looking for its dependencies : {}", templates_path.join(name), e);
+            return;
         }
     };
 
-    let tuples = templates.into_iter().map(|entry| {
-        let stamp = entry.metadata().unwrap().modified().unwrap();
-        let filename = &entry.file_name();
-        let n = Path::new(filename).file_stem().unwrap().to_str().unwrap();
-        (Layout::from(n), stamp)
-    });
+    let re = Regex::new(r#"\{%-?\s*(?:include|extends|import)\s+["']([^"']+)["']"#).unwrap();
+    for capture in re.captures_iter(&content) {
+        let dependency = capture[1].to_string();
+        if dependency != name && !found.contains(&dependency) {
+            found.push(dependency.clone());
+            collect_dependencies(templates_path, &dependency, found);
+        }
+    }
+}
 
-    let templates_registry: HashMap<Layout, SystemTime> = tuples.collect();
+/// Lists the layouts relying on the provided template file, either because it is the layout's template or because the layout extends or includes it.
+/// A deleted (or renamed) template is still reported as the template of the layout named after it, so that the pages using that layout are rendered again.
+pub fn dependent_layouts(templates_path: &Path, template_file: &Path) -> Vec<Layout> {
+    let changed = match template_file.strip_prefix(templates_path) {
+        Ok(p) => p.to_string_lossy().replace(MAIN_SEPARATOR, "/"),
+        Err(_) => return vec![],
+    };
 
-    Ok(templates_registry)
+    let mut layouts: Vec<Layout> = layout_templates(templates_path)
+        .iter()
+        .map(|name| Layout::from(name.as_str()))
+        .filter(|layout| {
+            let template = template_name(layout);
+            template == changed || dependencies(templates_path, &template).contains(&changed)
+        })
+        .collect();
+
+    if !template_file.exists() && !changed.contains('/') {
+        if let Some(name) = changed.strip_suffix(".html") {
+            let removed = Layout::from(name);
+            if !layouts.contains(&removed) {
+                layouts.push(removed);
+            }
+        }
+    }
+    layouts
 }
@@ -1,6 +1,15 @@
 use futures::{SinkExt, StreamExt};
-use log::{debug, error, info};
-use std::{collections::HashMap, fmt::Display, future, path::PathBuf, sync::Arc, time::Duration};
+use log::{debug, error, info, warn};
+use qrcode::{render::unicode::Dense1x2, QrCode};
+use std::{
+    collections::HashMap,
+    error::Error,
+    fmt::Display,
+    future,
+    net::{IpAddr, SocketAddr, ToSocketAddrs},
+    sync::Arc,
+    time::Duration,
+};
 use tokio::{
     sync::{
         mpsc::{self, UnboundedReceiver, UnboundedSender},
@@ -8,35 +17,253 @@ use tokio::{
     },
     time::sleep,
 };
+use serde::{Deserialize, Serialize};
+use simple_error::bail;
 use uuid::Uuid;
+
+use crate::preview::{Preview, Resource};
 use warp::{
     fs::File,
     http::HeaderValue,
-    hyper::{header::CONTENT_TYPE, Response},
+    http::StatusCode,
+    hyper::{
+        header::{CONTENT_TYPE, LOCATION},
+        Response,
+    },
+    path::FullPath,
     ws::{Message, WebSocket, Ws},
     Filter, Rejection, Reply,
 };
 
 const UPLINKJS: &str = r#"
-var sk = new WebSocket('ws://localhost:4200/uplink');
+var PROTOCOL_VERSION = __PROTOCOL_VERSION__;
+var sk = new WebSocket((location.protocol === 'https:' ? 'wss://' : 'ws://') + location.host + '/uplink');
+
+// Sends a message to the development server, __payload__ is omitted for actions which have none
+function send(action, payload) {
+    if (sk.readyState === WebSocket.OPEN) {
+        sk.send(JSON.stringify({ version: PROTOCOL_VERSION, action: action, payload: payload }));
+    }
+}
+
+// Asks the development server to render every page again, also available from the error overlay
+window.microRebuild = function () {
+    send('request-rebuild');
+};
+
+// Displays the details of a page which failed to render on top of the current page
+function showError(error) {
+    hideError();
+    var overlay = document.createElement('div');
+    overlay.id = 'micro-error-overlay';
+    overlay.style.cssText = 'position:fixed;top:0;left:0;right:0;bottom:0;z-index:2147483647;overflow:auto;padding:2em;'
+        + 'background:rgba(24,24,24,.95);color:#eee;font:14px/1.5 monospace;text-align:left;';
+
+    var title = document.createElement('h2');
+    title.style.color = '#ff6b6b';
+    var location = error.line ? ':' + error.line + (error.column ? ':' + error.column : '') : '';
+    title.textContent = 'Unable to render ' + error.file + location;
+
+    var origin = document.createElement('p');
+    if (error.template) {
+        origin.textContent = 'The error comes from the template ' + error.template + (error.template_line ? ' at line ' + error.template_line : '');
+    }
+
+    var message = document.createElement('pre');
+    message.style.whiteSpace = 'pre-wrap';
+    message.textContent = error.message;
+
+    var hint = document.createElement('p');
+    hint.style.color = '#999';
+    hint.textContent = 'This message disappears as soon as the page renders again.';
+
+    var rebuild = document.createElement('button');
+    rebuild.textContent = 'Rebuild all pages';
+    rebuild.addEventListener('click', window.microRebuild);
+
+    overlay.appendChild(title);
+    overlay.appendChild(origin);
+    overlay.appendChild(message);
+    overlay.appendChild(hint);
+    overlay.appendChild(rebuild);
+    document.body.appendChild(overlay);
+}
+
+function hideError() {
+    var overlay = document.getElementById('micro-error-overlay');
+    if (overlay) {
+        overlay.parentNode.removeChild(overlay);
+    }
+}
+
+// Fetches the updated stylesheets again, returns false if none of them is linked from the current page
+function swapStylesheets(paths) {
+    var swapped = false;
+    var links = document.querySelectorAll('link[rel="stylesheet"][href]');
+    for (var i = 0; i < links.length; i++) {
+        var link = links[i];
+        var url = new URL(link.href, document.location.href);
+        if (url.origin !== document.location.origin
+            || paths.indexOf(decodeURIComponent(url.pathname).replace(/^\//, '')) < 0) {
+            continue;
+        }
+        // The previous stylesheet is only dropped once the new one is loaded, avoiding a flash of unstyled content
+        url.searchParams.set('micro', Date.now());
+        var fresh = link.cloneNode();
+        fresh.href = url.href;
+        fresh.addEventListener('load', function () { this.previous.remove(); });
+        fresh.addEventListener('error', function () { this.previous.remove(); });
+        fresh.previous = link;
+        link.parentNode.insertBefore(fresh, link.nextSibling);
+        swapped = true;
+    }
+    return swapped;
+}
+
+// Path of the displayed page relative to the site root, as sent by the development server
+function currentPage() {
+    var current = decodeURIComponent(document.location.pathname).replace(/^\//, '');
+    if (current === '' || current.endsWith('/')) {
+        current += 'index.html';
+    }
+    return current;
+}
+
+// Stylesheet links only differ from their rendered version by the cache busting parameter once swapped
+function withoutCacheBusting(href) {
+    return href ? href.replace(/[?&]micro=\d+$/, '') : href;
+}
+
+function sameNode(from, to) {
+    return from.nodeType === to.nodeType && from.nodeName === to.nodeName && (from.id || '') === (to.id || '');
+}
+
+function morphAttributes(from, to) {
+    for (var i = from.attributes.length - 1; i >= 0; i--) {
+        var name = from.attributes[i].name;
+        // Opened <details> are part of the reader's state
+        if (!to.hasAttribute(name) && !(from.nodeName === 'DETAILS' && name === 'open')) {
+            from.removeAttribute(name);
+        }
+    }
+    for (var j = 0; j < to.attributes.length; j++) {
+        var attribute = to.attributes[j];
+        var current = from.getAttribute(attribute.name);
+        if (from.nodeName === 'LINK' && attribute.name === 'href' && withoutCacheBusting(current) === attribute.value) {
+            continue;
+        }
+        if (current !== attribute.value) {
+            from.setAttribute(attribute.name, attribute.value);
+        }
+    }
+}
+
+// Updates the __from__ node in place so that it matches __to__, only touching the nodes which differ.
+// Form fields keep what was typed in them since their value is never reset.
+function morph(from, to) {
+    if (from.nodeType !== Node.ELEMENT_NODE) {
+        if (from.nodeValue !== to.nodeValue) {
+            from.nodeValue = to.nodeValue;
+        }
+        return;
+    }
+    morphAttributes(from, to);
+
+    var targets = Array.prototype.slice.call(to.childNodes);
+    for (var i = 0; i < targets.length; i++) {
+        var target = targets[i];
+        var existing = from.childNodes[i];
+        if (!existing) {
+            from.appendChild(document.importNode(target, true));
+            continue;
+        }
+        if (existing.isEqualNode(target)) {
+            continue;
+        }
+        // A node found further down was kept while the ones before it were removed
+        var match = null;
+        for (var next = existing.nextSibling; next && !match; next = next.nextSibling) {
+            if (next.isEqualNode(target) || (target.id && next.id === target.id && sameNode(next, target))) {
+                match = next;
+            }
+        }
+        if (match) {
+            from.insertBefore(match, existing);
+            morph(match, target);
+        } else if (sameNode(existing, target)) {
+            morph(existing, target);
+        } else {
+            from.insertBefore(document.importNode(target, true), existing);
+        }
+    }
+    while (from.childNodes.length > targets.length) {
+        from.removeChild(from.lastChild);
+    }
+}
+
+// Brings the displayed page up to date with its freshly rendered html
+function patch(html) {
+    hideError();
+    var rendered = new DOMParser().parseFromString(html, 'text/html');
+    morph(document.head, rendered.head);
+    morph(document.body, rendered.body);
+}
+
+// Pages which failed to render when requested carry their error
+if (window.microError) {
+    showError(window.microError);
+}
 
 // Connection opened
 sk.addEventListener('open', function (event) {
     console.log('Connecting to development server');
-    sk.send('Hello');
+    send('hello', { url: currentPage() });
+    // Keeps the connection alive through proxies closing idle connections
+    setInterval(function () { send('ping'); }, 30000);
 });
 
 // Listen for messages
 sk.addEventListener('message', function (event) {
-    console.log(event.data);
     var msg = JSON.parse(event.data);
+    if (msg.version !== PROTOCOL_VERSION) {
+        console.warn('Ignoring a message sent using version ' + msg.version + ' of the uplink protocol, reload the page to update');
+        return;
+    }
+    if (msg.action !== 'pong') {
+        console.log(msg.action, msg.payload);
+    }
     switch(msg.action){
         case 'navigate':
             document.location = document.location.origin + '/' + msg.payload;
             break;
+        case 'patch':
+            if (msg.payload.url === currentPage()) {
+                patch(msg.payload.html);
+            } else {
+                document.location = document.location.origin + '/' + msg.payload.url;
+            }
+            break;
         case 'reload':
             document.location.reload();
             break;
+        case 'removed':
+            if (msg.payload.indexOf(currentPage()) >= 0) {
+                document.location = document.location.origin + '/';
+            } else {
+                document.location.reload();
+            }
+            break;
+        case 'css-update':
+            if (!swapStylesheets(msg.payload)) {
+                document.location.reload();
+            }
+            break;
+        case 'error':
+            showError(msg.payload);
+            break;
+        case 'build-status':
+            console.log(msg.payload.pages + ' page(s) rendered, ' + msg.payload.failed + ' failed in ' + msg.payload.duration_ms + 'ms');
+            break;
         case 'hello':
             console.log('Connected and listening for changes');
             break;
@@ -44,42 +271,141 @@ sk.addEventListener('message', function (event) {
 });
 "#;
 
+/// Number of consecutive ports tried when the requested one is already in use
+const PORT_ATTEMPTS: u16 = 20;
+
 type Result<T> = std::result::Result<T, Rejection>;
 /// Helper type used to store WebSocket connected client
 pub type Clients = Arc<RwLock<HashMap<String, Client>>>;
 
-pub enum ClientMessage{
+/// Version of the uplink protocol, sent along with every message so that outdated scripts can be detected
+pub const PROTOCOL_VERSION: u32 = 1;
+
+/// Details of a page which failed to render, displayed by the browsers in an overlay
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ErrorDetails {
+    /// Source file relative to the source directory
+    pub file: String,
+    pub message: String,
+    pub line: Option<usize>,
+    pub column: Option<usize>,
+    /// Template the error originates from, relative to the templates directory
+    pub template: Option<String>,
+    pub template_line: Option<usize>,
+}
+
+/// Outcome of the rendering of a set of pages
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct BuildStatus {
+    pub pages: usize,
+    pub failed: usize,
+    pub duration_ms: u64,
+}
+
+/// Messages sent by the development server to the browsers
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "action", content = "payload", rename_all = "kebab-case")]
+pub enum ClientMessage {
+    /// Acknowledges the hello message of a browser
+    #[serde(rename = "hello")]
     Handshake,
+    Pong,
     Reload,
-    Navigate(String)
+    Navigate(String),
+    /// A page was rendered again, the browsers displaying it update the changed parts in place while the others navigate to it
+    Patch {
+        url: String,
+        html: String,
+    },
+    /// Pages which were removed, the clients displaying one of them go back to the index while the others reload
+    Removed(Vec<String>),
+    /// A page failed to render, the browsers display the error until the next successful render
+    Error(ErrorDetails),
+    /// Stylesheets which were updated, the browsers fetch them again without reloading the page
+    CssUpdate(Vec<String>),
+    BuildStatus(BuildStatus),
+}
+
+/// Messages sent by the browsers to the development server
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "action", content = "payload", rename_all = "kebab-case")]
+pub enum ServerMessage {
+    /// Sent once connected, along with the url (relative to the site root) of the displayed page
+    Hello { url: String },
+    Ping,
+    /// Drops every page rendered in memory and renders them all again
+    RequestRebuild,
+}
+
+/// Wraps every message exchanged over the uplink WebSocket with the protocol version
+#[derive(Debug, Serialize, Deserialize)]
+struct Envelope<T> {
+    version: u32,
+    #[serde(flatten)]
+    message: T,
 }
 
-impl ClientMessage{
-    pub fn to_json(&self) -> String{
-        return match self {
-            ClientMessage::Handshake =>  String::from(r#"{"action":"hello"}"#),
+impl ClientMessage {
+    /// Serializes the message sent to the browsers, logging the error if it cannot be serialized
+    pub fn to_json(&self) -> Option<String> {
+        let envelope = Envelope {
+            version: PROTOCOL_VERSION,
+            message: self,
+        };
+        serde_json::to_string(&envelope)
+            .map_err(|e| error!("Unable to serialize the '{}' message: {}", self, e))
+            .ok()
+    }
+}
 
-            ClientMessage::Reload => 
-                 String::from(r#"{"action":"reload"}"#),
-            
-            ClientMessage::Navigate(path) =>
-                 format!("{{\"action\":\"navigate\", \"payload\":\"{}\"}}", path)
-            
+impl ServerMessage {
+    /// Parses a message received from a browser, rejecting the ones sent using another version of the protocol
+    pub fn from_json(json: &str) -> std::result::Result<ServerMessage, String> {
+        let envelope: Envelope<ServerMessage> = serde_json::from_str(json).map_err(|e| e.to_string())?;
+        if envelope.version != PROTOCOL_VERSION {
+            return Err(format!(
+                "protocol version {} is not supported, expected version {}",
+                envelope.version, PROTOCOL_VERSION
+            ));
         }
+        Ok(envelope.message)
     }
 }
 
-impl Display for ClientMessage{
+impl Display for ClientMessage {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(
-            f,
-            "{}",
-            match self {
-                ClientMessage::Handshake =>  String::from("Handshake"),
-                ClientMessage::Reload => String::from("Reload"),
-                ClientMessage::Navigate(path) => format!("Navigate:{}", path)
-            }
-        )
+        match self {
+            ClientMessage::Handshake => write!(f, "Handshake"),
+            ClientMessage::Pong => write!(f, "Pong"),
+            ClientMessage::Reload => write!(f, "Reload"),
+            ClientMessage::Navigate(path) => write!(f, "Navigate:{}", path),
+            ClientMessage::Patch { url, .. } => write!(f, "Patch:{}", url),
+            ClientMessage::Removed(paths) => write!(f, "Removed:{}", paths.join(",")),
+            ClientMessage::Error(details) => write!(f, "Error:{}", details.file),
+            ClientMessage::CssUpdate(paths) => write!(f, "CssUpdate:{}", paths.join(",")),
+            ClientMessage::BuildStatus(status) => write!(f, "BuildStatus:{}/{}", status.failed, status.pages),
+        }
+    }
+}
+
+/// Clients a message is meant for, according to the page they display
+#[derive(Debug, Clone, PartialEq)]
+pub enum Recipients {
+    All,
+    /// Clients displaying the provided page (relative to the site root)
+    Page(String),
+    /// Clients displaying any other page than the provided ones
+    Except(Vec<String>),
+}
+
+impl Recipients {
+    /// Returns true if a client displaying __url__ should receive the message, clients which did not tell what they display receive every message but the ones meant for a given page
+    fn includes(&self, url: Option<&str>) -> bool {
+        match self {
+            Recipients::All => true,
+            Recipients::Page(page) => url == Some(page.as_str()),
+            Recipients::Except(pages) => url.is_none_or(|u| !pages.iter().any(|p| p == u)),
+        }
     }
 }
 
@@ -87,6 +413,8 @@ impl Display for ClientMessage{
 #[derive(Debug)]
 pub struct Client {
     pub id: String,
+    /// Page displayed by the client, as announced in its hello message
+    pub url: Option<String>,
     pub sender: Option<mpsc::UnboundedSender<Message>>,
 }
 
@@ -103,59 +431,116 @@ impl DevServer {
         }
     }
 
-    /// Starts the local development web server, serving the content of __www_root__.
+    /// Starts the local development web server on __host__:__port__, serving the pages rendered by __preview__ along with the static assets of the source directory.
+    /// If __port__ is already in use, the next free port is used instead.
+    /// When __host__ is unspecified (0.0.0.0), every url the server can be reached at is listed along with a QR code.
     /// If __open_in_browser__ is set to true, the system's default browser will be openened at the specified __root_url__
     pub async fn serve(
         &self,
-        www_root: PathBuf,
+        preview: Arc<Preview>,
+        host: String,
         port: u16,
         open_in_browser: bool,
         root_url: Option<String>,
-    ) {
-        if !www_root.exists() {
-            panic!(format!(
-                "Cannot serve content from '{:#?}': the path does not exist",
-                www_root
-            ));
-        }
+    ) -> std::result::Result<(), Box<dyn Error + Send + Sync>> {
+        let ip = match (host.as_str(), port).to_socket_addrs().map(|mut a| a.next()) {
+            Ok(Some(a)) => a.ip(),
+            _ => bail!("Cannot start the development server: '{}:{}' is not a valid address", host, port),
+        };
 
         info!("Starting development server");
         let connexions = self.clients.clone();
+        let uplink_preview = preview.clone();
 
         // Uplink WebSocket
         let uplink = warp::path("uplink")
             .and(warp::ws())
             .and(warp::any().map(move || connexions.clone()))
+            .and(warp::any().map(move || uplink_preview.clone()))
             .and_then(register_ws_handler);
 
         // Uplink Javascript
         let uplinkjs = warp::get().and(warp::path("uplink.js")).map(|| {
             Response::builder()
                 .header("Content-Type", "text/javascript")
-                .body(UPLINKJS)
+                .body(UPLINKJS.replace("__PROTOCOL_VERSION__", &PROTOCOL_VERSION.to_string()))
         });
 
-        let root = warp::get().and(warp::fs::dir(www_root).and_then(inject_uplink));
+        let pages_preview = preview.clone();
+        let pages = warp::get()
+            .and(warp::path::full())
+            .and(warp::any().map(move || pages_preview.clone()))
+            .and_then(serve_page);
+
+        let assets_preview = preview.clone();
+        let assets = warp::get()
+            .and(warp::path::full())
+            .and(warp::any().map(move || assets_preview.clone()))
+            .and_then(|path: FullPath, preview: Arc<Preview>| async move {
+                match preview.resolve(path.as_str()) {
+                    Some(Resource::Asset(_)) => Ok(()),
+                    _ => Err(warp::reject::not_found()),
+                }
+            })
+            .untuple_one()
+            .and(warp::fs::dir(preview.root_path().to_path_buf()))
+            .and_then(inject_uplink);
 
+        let root = pages.or(assets);
         let filter = root.or(uplink);
         let withuplink = uplinkjs.or(filter);
-        let server = warp::serve(withuplink);
 
+        let mut bound = None;
+        let last_port = port.saturating_add(PORT_ATTEMPTS - 1);
+        for candidate in port..=last_port {
+            match warp::serve(withuplink.clone()).try_bind_ephemeral((ip, candidate)) {
+                Ok(b) => {
+                    bound = Some(b);
+                    break;
+                }
+                Err(e) => warn!("Unable to listen on port {} ({}), trying the next one", candidate, e),
+            }
+        }
+        let (address, server) = match bound {
+            Some(b) => b,
+            None => bail!(
+                "Cannot start the development server: no free port found between {} and {}",
+                port,
+                last_port
+            ),
+        };
         let path = match root_url {
             Some(u) => u,
             None => "/".to_string(),
         };
 
+        if ip.is_unspecified() {
+            let urls = local_urls(&address, &path);
+            info!("Development server listening on all interfaces, available at:");
+            for url in &urls {
+                info!("  {}", url);
+            }
+            if let Some(primary) = urls.iter().find(|u| !u.contains("localhost")) {
+                print_qr_code(primary);
+            }
+        } else {
+            info!("Development server listening on http://{}{}", address, path);
+        }
+
         if open_in_browser {
             tokio::task::spawn(async move {
                 // Lets delay the browser's opening
                 sleep(Duration::from_millis(5000)).await;
-                let url = format!("http://localhost:{}{}", &port, path);
-                webbrowser::open(&url).unwrap();
+                let browser_host = if ip.is_unspecified() { "localhost".to_string() } else { host };
+                let url = format!("http://{}:{}{}", browser_host, address.port(), path);
+                if let Err(e) = webbrowser::open(&url) {
+                    warn!("Unable to open {} in your browser: {}", url, e);
+                }
             });
         }
 
-        server.run(([127, 0, 0, 1], port)).await
+        server.await;
+        Ok(())
     }
 
     /// Returns the list clients connected via WebSocket
@@ -164,7 +549,108 @@ impl DevServer {
     }
 }
 
-/// For each page served by the development server we inject a reference to the /uplink.js file
+/// Lists the urls under which a server bound to an unspecified __address__ can be reached, local ones first
+fn local_urls(address: &SocketAddr, path: &str) -> Vec<String> {
+    let mut urls = vec![format!("http://localhost:{}{}", address.port(), path)];
+    match if_addrs::get_if_addrs() {
+        Ok(interfaces) => {
+            let mut addresses: Vec<IpAddr> = interfaces
+                .iter()
+                .filter(|i| !i.is_loopback() && !i.is_link_local())
+                .map(|i| i.ip())
+                .filter(|ip| ip.is_ipv4() == address.is_ipv4())
+                .collect();
+            addresses.sort();
+            addresses.dedup();
+            urls.extend(
+                addresses
+                    .into_iter()
+                    .map(|ip| format!("http://{}{}", SocketAddr::new(ip, address.port()), path)),
+            );
+        }
+        Err(e) => warn!("Unable to list the network interfaces: {}", e),
+    }
+    urls
+}
+
+/// Prints a QR code pointing to __url__ so that the site can easily be opened from a mobile device
+fn print_qr_code(url: &str) {
+    match QrCode::new(url.as_bytes()) {
+        Ok(code) => {
+            let image = code
+                .render::<Dense1x2>()
+                .dark_color(Dense1x2::Light)
+                .light_color(Dense1x2::Dark)
+                .build();
+            println!("\n{}\n", image);
+        }
+        Err(e) => warn!("Unable to generate a QR code for {}: {}", url, e),
+    }
+}
+
+/// Serves the page rendered from the markdown file designated by the request path, rejecting the request if there is none.
+/// Directories requested without a trailing slash are redirected to their slash terminated url.
+async fn serve_page(path: FullPath, preview: Arc<Preview>) -> Result<impl Reply> {
+    let source = match preview.resolve(path.as_str()) {
+        Some(Resource::Page(source)) => source,
+        Some(Resource::Redirect(location)) => {
+            let mut response = Response::new(warp::hyper::Body::empty());
+            *response.status_mut() = StatusCode::MOVED_PERMANENTLY;
+            match HeaderValue::from_str(&location) {
+                Ok(l) => response.headers_mut().append(LOCATION, l),
+                Err(_) => return Err(warp::reject::not_found()),
+            };
+            return Ok(response);
+        }
+        _ => return Err(warp::reject::not_found()),
+    };
+
+    let rendering = preview.clone();
+    let rendered = match tokio::task::spawn_blocking(move || rendering.render(&source)).await {
+        Ok(r) => r,
+        Err(e) => {
+            error!("Unable to render {}: {}", path.as_str(), e);
+            return Err(warp::reject::not_found());
+        }
+    };
+
+    let (status, content) = match rendered {
+        Ok(page) => (StatusCode::OK, page),
+        Err(e) => {
+            error!("{}", e);
+            // The uplink script displays the error in an overlay
+            let details = serde_json::to_string(&preview.error_details(&e)).unwrap_or_else(|_| String::from("{}"));
+            (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                format!(
+                    "<html><body><pre>{}</pre><script>window.microError = {};</script></body></html>",
+                    tera::escape_html(&e.message),
+                    details.replace("</", "<\\/")
+                ),
+            )
+        }
+    };
+
+    let mut response = Response::new(warp::hyper::Body::from(with_uplink(&content)));
+    *response.status_mut() = status;
+    response
+        .headers_mut()
+        .append(CONTENT_TYPE, HeaderValue::from_static("text/html; charset=utf-8"));
+    Ok(response)
+}
+
+/// Adds a reference to the /uplink.js file at the end of the provided html document
+pub fn with_uplink(content: &str) -> String {
+    content.replace(
+        "</body>",
+        r#"
+        </body>
+        <script type="text/javascript" src="/uplink.js"></script>
+    "#,
+    )
+}
+
+/// For each static html file served by the development server we inject a reference to the /uplink.js file
 async fn inject_uplink(file: File) -> Result<impl Reply> {
     let mut response = file.into_response();
     let headers = response.headers();
@@ -186,7 +672,7 @@ async fn inject_uplink(file: File) -> Result<impl Reply> {
     let mut buffer: Vec<u8> = Vec::new();
     body.for_each(|chunk| {
         if let Ok(bytes) = chunk {
-            &buffer.extend_from_slice(&bytes[..]);
+            buffer.extend_from_slice(&bytes[..]);
         }
         future::ready(())
     })
@@ -198,27 +684,19 @@ async fn inject_uplink(file: File) -> Result<impl Reply> {
             return Ok(response);
         }
     };
-    // TODO make port a parameter
-    let replaced = content.replace(
-        "</body>",
-        r#"
-        </body>
-        <script type="text/javascript" src="http://localhost:4200/uplink.js"></script>
-    "#,
-    );
-    let mut resp = Response::new(warp::hyper::Body::from(replaced));
+    let mut resp = Response::new(warp::hyper::Body::from(with_uplink(&content)));
     resp.headers_mut()
         .append(CONTENT_TYPE, HeaderValue::from_static("text/html"));
     Ok(resp)
 }
 
 /// Registers the WebSocket connection handler
-async fn register_ws_handler(ws: Ws, clients: Clients) -> Result<impl Reply> {
-    Ok(ws.on_upgrade(move |socket| con_client_connected(socket, clients)))
+async fn register_ws_handler(ws: Ws, clients: Clients, preview: Arc<Preview>) -> Result<impl Reply> {
+    Ok(ws.on_upgrade(move |socket| con_client_connected(socket, clients, preview)))
 }
 
 /// Called whenever a new client connects via websockets
-async fn con_client_connected(ws: WebSocket, clients: Clients) {
+async fn con_client_connected(ws: WebSocket, clients: Clients, preview: Arc<Preview>) {
     debug!("New WS client connection request received");
 
     let (mut client_ws_sender, mut client_ws_rcv) = ws.split();
@@ -235,6 +713,7 @@ async fn con_client_connected(ws: WebSocket, clients: Clients) {
 
     let client = Client {
         id: id.clone(),
+        url: None,
         sender: Some(client_sender),
     };
 
@@ -250,7 +729,7 @@ async fn con_client_connected(ws: WebSocket, clients: Clients) {
                 break;
             }
         };
-        client_msg(&id, msg, &clients).await;
+        client_msg(&id, msg, &clients, &preview).await;
     }
 
     clients.write().await.remove(&id);
@@ -258,23 +737,209 @@ async fn con_client_connected(ws: WebSocket, clients: Clients) {
 }
 
 /// Triggered whenever a message is received via WebSocket
-async fn client_msg(id: &str, msg: Message, clients: &Clients) {
-    let mut locked = clients.write().await;
-    if let Some(v) = locked.get_mut(id) {
-        debug!("Message received from '{}': {:#?}", v.id, msg);
-        if let Some(sender) = &v.sender {
-            let _ = sender.send(Message::text(ClientMessage::Handshake.to_json()));
+async fn client_msg(id: &str, msg: Message, clients: &Clients, preview: &Arc<Preview>) {
+    // Pings, pongs and close frames are handled by warp
+    let text = match msg.to_str() {
+        Ok(t) => t,
+        Err(_) => return,
+    };
+    let message = match ServerMessage::from_json(text) {
+        Ok(m) => m,
+        Err(e) => {
+            warn!("Ignoring invalid message from client '{}' ({}): {}", id, e, text);
+            return;
+        }
+    };
+    debug!("Message received from '{}': {:?}", id, message);
+
+    match message {
+        ServerMessage::Hello { url } => {
+            info!("Live preview instance '{}' displays '{}'", id, url);
+            if let Some(client) = clients.write().await.get_mut(id) {
+                client.url = Some(url);
+            }
+            send_to(clients, id, ClientMessage::Handshake).await;
         }
+        ServerMessage::Ping => send_to(clients, id, ClientMessage::Pong).await,
+        ServerMessage::RequestRebuild => {
+            info!("Rebuild requested by live preview instance '{}'", id);
+            let rendering = preview.clone();
+            match tokio::task::spawn_blocking(move || rendering.rebuild()).await {
+                Ok(messages) => {
+                    for (recipients, message) in messages {
+                        send_message_to(clients, &recipients, message).await;
+                    }
+                }
+                Err(e) => error!("Unable to rebuild the pages: {}", e),
+            }
+        }
+    }
+}
+
+/// Sends a message to the WebSocket connected client identified by __id__
+async fn send_to(clients: &Clients, id: &str, message: ClientMessage) {
+    let json = match message.to_json() {
+        Some(json) => json,
+        None => return,
+    };
+    if let Some(sender) = clients.read().await.get(id).and_then(|c| c.sender.as_ref()) {
+        let _ = sender.send(Message::text(json));
     }
 }
 
-/// Sends a message to the provided list of WebSocket connect clients
-pub async fn send_message(clients: &Clients, message: ClientMessage) {
-    clients.read().await.iter().for_each(|(_, client)| {
-        debug!("Notifiying client '{}' for '{}'", client.id, message);
-        if let Some(sender) = &client.sender {
-            //let _ = sender.send(Message::text(path.clone()));
-            let _ = sender.send(Message::text(message.to_json()));
+/// Sends a message to the WebSocket connected clients displaying the pages designated by __recipients__
+pub async fn send_message_to(clients: &Clients, recipients: &Recipients, message: ClientMessage) {
+    let json = match message.to_json() {
+        Some(json) => json,
+        None => return,
+    };
+    clients
+        .read()
+        .await
+        .iter()
+        .filter(|(_, client)| recipients.includes(client.url.as_deref()))
+        .for_each(|(_, client)| {
+            debug!(
+                "Notifiying client '{}' ({}) for '{}'",
+                client.id,
+                client.url.as_deref().unwrap_or("unknown page"),
+                message
+            );
+            if let Some(sender) = &client.sender {
+                let _ = sender.send(Message::text(json.clone()));
+            }
+        });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::{json, Value};
+
+    fn wire(message: &ClientMessage) -> Value {
+        serde_json::from_str(&message.to_json().unwrap()).unwrap()
+    }
+
+    #[test]
+    fn server_messages_carry_the_protocol_version() {
+        assert_eq!(wire(&ClientMessage::Reload), json!({"version": 1, "action": "reload"}));
+        assert_eq!(wire(&ClientMessage::Handshake), json!({"version": 1, "action": "hello"}));
+        assert_eq!(wire(&ClientMessage::Pong), json!({"version": 1, "action": "pong"}));
+    }
+
+    #[test]
+    fn navigate_paths_are_escaped() {
+        let message = ClientMessage::Navigate(String::from(r#"articles/"quoted"\path.html"#));
+        assert_eq!(
+            wire(&message),
+            json!({"version": 1, "action": "navigate", "payload": r#"articles/"quoted"\path.html"#})
+        );
+    }
+
+    #[test]
+    fn server_messages_payloads() {
+        assert_eq!(
+            wire(&ClientMessage::CssUpdate(vec![String::from("styles.css")])),
+            json!({"version": 1, "action": "css-update", "payload": ["styles.css"]})
+        );
+        assert_eq!(
+            wire(&ClientMessage::Patch {
+                url: String::from("index.html"),
+                html: String::from("<p>\"hi\"</p>"),
+            }),
+            json!({"version": 1, "action": "patch", "payload": {"url": "index.html", "html": "<p>\"hi\"</p>"}})
+        );
+        assert_eq!(
+            wire(&ClientMessage::Error(ErrorDetails {
+                file: String::from("index.md"),
+                message: String::from("Invalid YAML metadata"),
+                line: Some(3),
+                column: None,
+                template: None,
+                template_line: None,
+            })),
+            json!({
+                "version": 1,
+                "action": "error",
+                "payload": {
+                    "file": "index.md",
+                    "message": "Invalid YAML metadata",
+                    "line": 3,
+                    "column": null,
+                    "template": null,
+                    "template_line": null
+                }
+            })
+        );
+        assert_eq!(
+            wire(&ClientMessage::Error(ErrorDetails {
+                file: String::from("index.md"),
+                message: String::from("Failed to render 'article.html'"),
+                line: None,
+                column: None,
+                template: Some(String::from("partials/head.html")),
+                template_line: Some(12),
+            }))["payload"],
+            json!({
+                "file": "index.md",
+                "message": "Failed to render 'article.html'",
+                "line": null,
+                "column": null,
+                "template": "partials/head.html",
+                "template_line": 12
+            })
+        );
+        assert_eq!(
+            wire(&ClientMessage::BuildStatus(BuildStatus {
+                pages: 12,
+                failed: 1,
+                duration_ms: 40,
+            })),
+            json!({"version": 1, "action": "build-status", "payload": {"pages": 12, "failed": 1, "duration_ms": 40}})
+        );
+    }
+
+    #[test]
+    fn server_messages_round_trip() {
+        let messages = vec![
+            ClientMessage::Handshake,
+            ClientMessage::Reload,
+            ClientMessage::Navigate(String::from("article/article.html")),
+            ClientMessage::Removed(vec![String::from("a.html"), String::from("b.html")]),
+            ClientMessage::CssUpdate(vec![]),
+        ];
+        for message in messages {
+            let envelope: Envelope<ClientMessage> = serde_json::from_str(&message.to_json().unwrap()).unwrap();
+            assert_eq!(envelope.version, PROTOCOL_VERSION);
+            assert_eq!(envelope.message, message);
         }
-    });
+    }
+
+    #[test]
+    fn parses_client_messages() {
+        assert_eq!(
+            ServerMessage::from_json(r#"{"version":1,"action":"hello","payload":{"url":"article/article.html"}}"#),
+            Ok(ServerMessage::Hello {
+                url: String::from("article/article.html")
+            })
+        );
+        assert_eq!(ServerMessage::from_json(r#"{"version":1,"action":"ping"}"#), Ok(ServerMessage::Ping));
+        assert_eq!(
+            ServerMessage::from_json(r#"{"version":1,"action":"request-rebuild"}"#),
+            Ok(ServerMessage::RequestRebuild)
+        );
+    }
+
+    #[test]
+    fn rejects_invalid_client_messages() {
+        // Unsupported protocol version
+        assert!(ServerMessage::from_json(r#"{"version":2,"action":"ping"}"#).is_err());
+        // Missing protocol version, as sent by the scripts predating the protocol
+        assert!(ServerMessage::from_json("Hello").is_err());
+        assert!(ServerMessage::from_json(r#"{"action":"ping"}"#).is_err());
+        // Unknown action
+        assert!(ServerMessage::from_json(r#"{"version":1,"action":"shutdown"}"#).is_err());
+        // Missing payload
+        assert!(ServerMessage::from_json(r#"{"version":1,"action":"hello"}"#).is_err());
+    }
 }
@@ -1,11 +1,14 @@
 use log::warn;
+use sha2::{Digest, Sha256};
 use std::{
+    cell::RefCell,
     ffi::OsStr,
+    fs, io,
     path::{Path, PathBuf},
 };
 
-/// Scans the provided path for file with the specified extension and calls the handling function for each occurence
-pub fn walk_dir<F>(path: PathBuf, extension: &'static str, recursive: bool, handler: &F)
+/// Scans the provided path for file with the specified extension (or any file if no extension is provided) and calls the handling function for each occurence
+pub fn walk_dir<F>(path: PathBuf, extension: Option<&'static str>, recursive: bool, handler: &F)
 where
     F: Fn(&Path)
 {
@@ -16,7 +19,7 @@ where
                 "Unable to read '{:#?}' content, the directory will be skipped : {}",
                 &path, e
             );
-            return ();
+            return;
         }
     };
 
@@ -44,7 +47,7 @@ where
         };
 
         if metadata.is_file()  {
-            if item_path.extension() == Some(OsStr::new(extension)){
+            if extension.is_none() || item_path.extension() == extension.map(OsStr::new) {
                 handler(&item_path);
             }
         } else if recursive {
@@ -52,3 +55,30 @@ where
         }
     }
 }
+
+/// Lists, sorted by path, the files of the provided directory and its sub directories with the specified extension (or all the files if no extension is provided)
+pub fn list_files(path: &Path, extension: Option<&'static str>) -> Vec<PathBuf> {
+    let files = RefCell::new(vec![]);
+    walk_dir(path.to_path_buf(), extension, true, &|p: &Path| files.borrow_mut().push(p.to_path_buf()));
+    let mut files = files.into_inner();
+    files.sort();
+    files
+}
+
+/// Returns the SHA-256 hash of the provided content as an hexadecimal string
+pub fn hash(content: &[u8]) -> String {
+    format!("{:x}", Sha256::digest(content))
+}
+
+/// Returns the SHA-256 hash of the provided file content as an hexadecimal string
+pub fn hash_file(path: &Path) -> io::Result<String> {
+    Ok(hash(&fs::read(path)?))
+}
+
+/// Returns the path mirroring the provided source file (located under source_root) in the target_root directory
+pub fn mirror_path(source: &Path, source_root: &Path, target_root: &Path) -> PathBuf {
+    match source.strip_prefix(source_root) {
+        Ok(relative) => target_root.join(relative),
+        Err(_) => target_root.join(source.file_name().unwrap_or_default()),
+    }
+}
@@ -0,0 +1,312 @@
+use std::{
+    cell::RefCell,
+    collections::HashMap,
+    error::Error,
+    fmt::Display,
+    fs,
+    path::{Path, PathBuf},
+};
+
+use chrono::{DateTime, NaiveDate, NaiveDateTime};
+use log::{error, info};
+use serde::Deserialize;
+use serde_yaml::Value;
+use simple_error::bail;
+
+use crate::convert::{frontmatter, metadata::MarkdownMetaData, template};
+use crate::filesystem::walk_dir;
+
+/// Front matter schema the pages are validated against
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Schema {
+    /// Fields required by every page whatever its layout
+    #[serde(default)]
+    pub required: Vec<String>,
+    /// Rules applying to the fields of every page whatever its layout
+    #[serde(default)]
+    pub fields: HashMap<String, FieldRule>,
+    /// Additional rules per layout name
+    #[serde(default)]
+    pub layouts: HashMap<String, LayoutRules>,
+}
+
+/// Rules applying to the pages using a given layout
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct LayoutRules {
+    #[serde(default)]
+    pub required: Vec<String>,
+    /// Rules overriding the ones declared at the schema level
+    #[serde(default)]
+    pub fields: HashMap<String, FieldRule>,
+}
+
+/// Rules applying to a single front matter field
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct FieldRule {
+    #[serde(rename = "type")]
+    pub kind: Option<FieldType>,
+    /// Values the field is allowed to take, applies to the items of lists
+    #[serde(default)]
+    pub allowed: Vec<Value>,
+    /// Format of date fields (chrono syntax), defaults to RFC 3339
+    pub format: Option<String>,
+}
+
+#[derive(Debug, Clone, Copy, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum FieldType {
+    String,
+    Number,
+    Integer,
+    Boolean,
+    Date,
+    List,
+    Map,
+}
+
+impl Display for FieldType {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(
+            f,
+            "{}",
+            match self {
+                FieldType::String => "string",
+                FieldType::Number => "number",
+                FieldType::Integer => "integer",
+                FieldType::Boolean => "boolean",
+                FieldType::Date => "date",
+                FieldType::List => "list",
+                FieldType::Map => "map",
+            }
+        )
+    }
+}
+
+/// A schema violation found in a page
+#[derive(Debug)]
+pub struct Violation {
+    pub file: PathBuf,
+    pub message: String,
+}
+
+impl Display for Violation {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{:#?}: {}", self.file, self.message)
+    }
+}
+
+impl Schema {
+    /// Loads the schema from the provided YAML file
+    pub fn from_file(path: &Path) -> Result<Schema, Box<dyn Error>> {
+        let content = match fs::read_to_string(path) {
+            Ok(c) => c,
+            Err(e) => bail!("Unable to read the front matter schema {:#?} : {}", path, e),
+        };
+        match serde_yaml::from_str(&content) {
+            Ok(s) => Ok(s),
+            Err(e) => bail!("Invalid front matter schema {:#?} : {}", path, e),
+        }
+    }
+
+    /// Returns the rules declared for the given layout, layout names being case-insensitive
+    fn layout_rules(&self, layout: &str) -> Option<&LayoutRules> {
+        self.layouts
+            .iter()
+            .find(|(name, _)| name.eq_ignore_ascii_case(layout))
+            .map(|(_, rules)| rules)
+    }
+
+    /// Returns the rule applying to a field for the given layout, layout rules taking precedence over the global ones
+    fn rule(&self, layout: &str, field: &str) -> Option<&FieldRule> {
+        self.layout_rules(layout)
+            .and_then(|l| l.fields.get(field))
+            .or_else(|| self.fields.get(field))
+    }
+
+    /// Validates the front matter of a markdown page and returns the violations found
+    pub fn validate(&self, document: &str, templates_path: &Path) -> Vec<String> {
+        let front_matter = match frontmatter::parse(document) {
+            Ok(Some(f)) => f,
+            Ok(None) => return vec![String::from("Missing metadata")],
+            Err(e) => return vec![e.to_string()],
+        };
+
+        let metadata = match MarkdownMetaData::from_yaml(front_matter.value.clone()) {
+            Some(m) => m,
+            None => return vec![String::from("Missing mandatory metadata field \"layout\"")],
+        };
+
+        let mut violations = vec![];
+        let layout = metadata.layout.name();
+        if !template::available_layouts(templates_path).iter().any(|l| l.eq_ignore_ascii_case(layout)) {
+            violations.push(format!("Unknown layout '{}'", layout));
+        }
+
+        let fields = match front_matter.value.as_mapping() {
+            Some(m) => m.clone(),
+            None => Default::default(),
+        };
+
+        let layout_required = self.layout_rules(layout).map(|l| &l.required[..]).unwrap_or(&[]);
+        for field in self.required.iter().chain(layout_required) {
+            if !fields.contains_key(&Value::String(field.clone())) {
+                violations.push(format!("Missing required field '{}' for layout '{}'", field, layout));
+            }
+        }
+
+        for (key, value) in fields.iter() {
+            let name = match key.as_str() {
+                Some(n) => n,
+                None => continue,
+            };
+            if let Some(rule) = self.rule(layout, name) {
+                violations.extend(rule.check(value).into_iter().map(|m| format!("Field '{}' {}", name, m)));
+            }
+        }
+
+        violations
+    }
+}
+
+impl FieldRule {
+    /// Checks the provided value against the rule and returns the violations found
+    fn check(&self, value: &Value) -> Vec<String> {
+        let mut violations = vec![];
+
+        if let Some(kind) = self.kind {
+            if let Some(message) = check_type(kind, value, self.format.as_deref()) {
+                violations.push(message);
+            }
+        }
+
+        if !self.allowed.is_empty() {
+            let items = match value {
+                Value::Sequence(items) => items.iter().collect(),
+                other => vec![other],
+            };
+            for item in items {
+                if !self.allowed.contains(item) {
+                    violations.push(format!(
+                        "has value {} which is not one of the allowed values {}",
+                        describe(item),
+                        self.allowed.iter().map(describe).collect::<Vec<String>>().join(", ")
+                    ));
+                }
+            }
+        }
+
+        violations
+    }
+}
+
+fn check_type(kind: FieldType, value: &Value, format: Option<&str>) -> Option<String> {
+    let valid = match kind {
+        FieldType::String => value.is_string(),
+        FieldType::Number => value.is_number(),
+        FieldType::Integer => value.is_i64() || value.is_u64(),
+        FieldType::Boolean => value.is_bool(),
+        FieldType::List => value.is_sequence(),
+        FieldType::Map => value.is_mapping(),
+        FieldType::Date => {
+            return match value.as_str() {
+                Some(s) if is_date(s, format) => None,
+                _ => Some(format!(
+                    "has value {} which is not a date formatted as {}",
+                    describe(value),
+                    format.unwrap_or("RFC 3339")
+                )),
+            };
+        }
+    };
+
+    if valid {
+        None
+    } else {
+        Some(format!("has value {} which is not of type {}", describe(value), kind))
+    }
+}
+
+fn is_date(s: &str, format: Option<&str>) -> bool {
+    match format {
+        Some(f) => {
+            DateTime::parse_from_str(s, f).is_ok()
+                || NaiveDateTime::parse_from_str(s, f).is_ok()
+                || NaiveDate::parse_from_str(s, f).is_ok()
+        }
+        None => DateTime::parse_from_rfc3339(s).is_ok(),
+    }
+}
+
+fn describe(value: &Value) -> String {
+    match serde_json::to_string(value) {
+        Ok(s) => s,
+        Err(_) => format!("{:?}", value),
+    }
+}
+
+/// Validates the front matter of every markdown file found in the source directory against the provided schema.
+/// All the violations are reported at once, returns the number of violations found.
+pub fn lint(root_path: PathBuf, templates_path: PathBuf, schema_path: &Path) -> Result<usize, Box<dyn Error>> {
+    let schema = Schema::from_file(schema_path)?;
+    let violations: RefCell<Vec<Violation>> = RefCell::new(vec![]);
+
+    walk_dir(root_path, Some("md"), true, &|p: &Path| {
+        let messages = match fs::read_to_string(p) {
+            Ok(content) => schema.validate(&content, &templates_path),
+            Err(e) => vec![format!("Unable to read the file : {}", e)],
+        };
+        violations.borrow_mut().extend(messages.into_iter().map(|message| Violation {
+            file: p.to_path_buf(),
+            message,
+        }));
+    });
+
+    let violations = violations.into_inner();
+    for violation in &violations {
+        error!("{}", violation);
+    }
+
+    let mut files: Vec<&PathBuf> = violations.iter().map(|v| &v.file).collect();
+    files.dedup();
+    if violations.is_empty() {
+        info!("No front matter violation found");
+    } else {
+        error!("{} violation(s) found in {} file(s)", violations.len(), files.len());
+    }
+
+    Ok(violations.len())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn schema() -> Schema {
+        serde_yaml::from_str(
+            r#"
+layouts:
+  article:
+    required: [description]
+    fields:
+      status:
+        allowed: [draft, published]
+"#,
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn layout_rules_ignore_the_layout_case() {
+        let violations = schema().validate("---\nlayout: Article\ntitle: T\nstatus: lost\n---\n", Path::new("templates"));
+        assert_eq!(
+            violations,
+            vec![
+                String::from("Missing required field 'description' for layout 'Article'"),
+                String::from(r#"Field 'status' has value "lost" which is not one of the allowed values "draft", "published""#),
+            ]
+        );
+    }
+}
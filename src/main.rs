@@ -1,21 +1,38 @@
+mod assets;
+mod config;
 mod convert;
 mod devserver;
 mod filesystem;
+mod lint;
+mod manifest;
+mod preview;
 mod watcher;
 
 use std::{
+    cell::RefCell,
     env,
     error::Error,
     ffi::OsStr,
-    path::{Path, PathBuf, MAIN_SEPARATOR},
+    fs,
+    path::{Path, PathBuf},
+    process,
+    sync::Arc,
+    time::Instant,
 };
 
+use assets::AssetFilter;
 use clap::{App, Arg};
-use convert::{markdown_to_html, metadata, template};
-use devserver::{ClientMessage, DevServer};
-use filesystem::walk_dir;
-use log::{error, info};
+use config::Config;
+use convert::{parse_markdown, read_markdown, render_page, template, write_html, MarkdownPage, RenderError, RenderSettings};
+use devserver::{ClientMessage, DevServer, Recipients};
+use filesystem::{list_files, mirror_path, walk_dir};
+use log::{error, info, warn};
+use manifest::{Inputs, Manifest, Outdated};
+use preview::Preview;
+use rayon::prelude::*;
 use simple_error::bail;
+use tera::Tera;
+use watcher::Change;
 use tokio::{
     join,
     sync::mpsc::{Receiver, Sender},
@@ -23,7 +40,7 @@ use tokio::{
 
 #[tokio::main]
 async fn main() {
-    if let Err(_) = env::var("RUST_LOG") {
+    if env::var("RUST_LOG").is_err() {
         env::set_var("RUST_LOG", "INFO");
     }
 
@@ -32,17 +49,16 @@ async fn main() {
     let matches = App::new("micro")
     .author("by FredJeck")
     .about("A super simple static website generator")
+    .arg(Arg::new("CONFIG")
+        .short('c')
+        .long("config")
+        .takes_value(true)
+        .about("Path to the configuration file (defaults to the file named 'micro.toml' in the current directory if it exists). Command line arguments take precedence over the configuration file values"))
     .arg(Arg::new("SOURCE")
         .short('s')
         .long("src")
-        .about("Path to the directory where the markdown source files are stored")
-        .default_value(env::current_dir().unwrap().join("wwwroot").to_str().unwrap())
-        .validator(|p|{
-            if !Path::new(p).exists(){
-                return Err(format!("Unable to find '{}'. Please make sure the 'src' argument points to your source files directory (defaults to the folder named 'wwwroot' in the current directory)", p));
-            }
-            Ok(())
-        }))
+        .takes_value(true)
+        .about("Path to the directory where the markdown source files are stored (defaults to the folder named 'wwwroot' in the current directory)"))
     .arg(Arg::new("DEV")
         .short('d')
         .long("dev")
@@ -51,15 +67,58 @@ async fn main() {
     .arg(Arg::new("TEMPLATES")
         .short('t')
         .long("templates")
-        .about("Path to the directory where the pages templates are located")
-        .default_value(env::current_dir().unwrap().join("templates").to_str().unwrap())
-        .validator(|p|{
-            if !Path::new(p).exists(){
-                return Err(format!("Unable to find '{}'. Please make sure the 'templates' argument points to the directory containing your html templates files (defaults to the folder named 'templates' in the current directory)", p));
-            }
-            Ok(())
+        .takes_value(true)
+        .about("Path to the directory where the pages templates are located (defaults to the folder named 'templates' in the current directory)"))
+    .arg(Arg::new("OUTPUT")
+        .short('o')
+        .long("out")
+        .takes_value(true)
+        .about("Path to the directory where the generated html files are written, mirroring the source directory structure (defaults to the folder named 'public' in the current directory)"))
+    .arg(Arg::new("HOST")
+        .long("host")
+        .takes_value(true)
+        .about("Address the development server listens on (defaults to 127.0.0.1)"))
+    .arg(Arg::new("PORT")
+        .short('p')
+        .long("port")
+        .takes_value(true)
+        .about("Port the development server listens on, the next free port is used if it is already in use (defaults to 4200)")
+        .validator(|p| match p.parse::<u16>() {
+            Ok(_) => Ok(()),
+            Err(_) => Err(format!("'{}' is not a valid port number", p)),
+        }))
+    .arg(Arg::new("POLL")
+        .long("poll")
+        .takes_value(false)
+        .about("In development mode, periodically rescans the source and templates directories instead of relying on the operating system file change notifications"))
+    .arg(Arg::new("JOBS")
+        .short('j')
+        .long("jobs")
+        .takes_value(true)
+        .about("Number of pages rendered concurrently (defaults to the number of available cores)")
+        .validator(|j| match j.parse::<usize>() {
+            Ok(n) if n > 0 => Ok(()),
+            _ => Err(format!("'{}' is not a valid number of jobs", j)),
         }))
+    .arg(Arg::new("IGNORE")
+        .short('i')
+        .long("ignore")
+        .takes_value(true)
+        .multiple_occurrences(true)
+        .about("Glob pattern of the source files which should not be copied to the output directory, can be repeated. Hidden files are always ignored"))
+    .subcommand(
+        App::new("clean").about("Removes the files micro generated in the output directory which source was deleted or renamed, or which are now ignored. Files micro did not write are left untouched")
+        .arg(Arg::new("DRYRUN")
+            .long("dry-run")
+            .takes_value(false)
+            .about("Lists the files which would be removed without removing them")))
     .subcommand(App::new("verify").about("Scans your source files for outdated or unpublished pages - this command does not publish outdated elements"))
+    .subcommand(
+        App::new("lint").about("Validates the metadata of all your source files against a schema and reports all the violations found. Exits with a non zero status code if any violation is found")
+        .arg(Arg::new("SCHEMA")
+            .long("schema")
+            .takes_value(true)
+            .about("Path to the YAML file declaring the metadata schema (defaults to the file named 'schema.yaml' in the current directory)")))
     .subcommand(
         App::new("publish").about("Scans your source files for outdated or unpublished pages. This command will republish all the outdated or unpblished elements but also republish all the pages which template has been updated")
         .arg(Arg::new("FORCE")
@@ -69,158 +128,457 @@ async fn main() {
             .about("Re-publishes all the source markdown files even if no changes were detected")))
     .get_matches();
 
-    let root_path = match matches.value_of("SOURCE") {
-        Some(s) => PathBuf::from(s),
-        None => panic!("Source path cannot be found"),
+    let mut config = match Config::load(matches.value_of("CONFIG").map(Path::new)) {
+        Ok(c) => c,
+        Err(e) => {
+            error!("{}", e);
+            process::exit(1);
+        }
     };
-    let templates_path = match matches.value_of("TEMPLATES") {
-        Some(s) => PathBuf::from(s),
-        None => panic!("Templates path cannot be found"),
+
+    let cwd = env::current_dir().unwrap();
+    if let Some(s) = matches.value_of("SOURCE") {
+        config.paths.source = cwd.join(s);
+    }
+    if let Some(s) = matches.value_of("TEMPLATES") {
+        config.paths.templates = cwd.join(s);
+    }
+    if let Some(s) = matches.value_of("OUTPUT") {
+        config.paths.output = cwd.join(s);
+    }
+    if let Some(h) = matches.value_of("HOST") {
+        config.server.host = h.to_string();
+    }
+    if let Some(p) = matches.value_of("PORT") {
+        config.server.port = p.parse().unwrap();
+    }
+    if 1 == matches.occurrences_of("POLL") {
+        config.watcher.poll = true;
+    }
+    if let Some(j) = matches.value_of("JOBS") {
+        config.build.jobs = j.parse().unwrap();
+    }
+    if let Some(v) = matches.values_of("IGNORE") {
+        config.assets.ignore = v.map(String::from).collect();
+    }
+    if let Some(s) = matches.subcommand_matches("lint").and_then(|sbc| sbc.value_of("SCHEMA")) {
+        config.lint.schema = cwd.join(s);
+    }
+
+    if !config.paths.source.exists() {
+        error!("Unable to find {:#?}. Please make sure the 'src' argument or the 'paths.source' setting points to your source files directory (defaults to the folder named 'wwwroot' in the current directory)", config.paths.source);
+        process::exit(1);
+    }
+    if !config.paths.templates.exists() {
+        error!("Unable to find {:#?}. Please make sure the 'templates' argument or the 'paths.templates' setting points to the directory containing your html templates files (defaults to the folder named 'templates' in the current directory)", config.paths.templates);
+        process::exit(1);
+    }
+
+    if let Err(e) = rayon::ThreadPoolBuilder::new().num_threads(config.build.jobs).build_global() {
+        warn!("Unable to set the number of rendering jobs, using the default: {}", e);
+    }
+
+    let assets = match config.asset_filter() {
+        Ok(a) => a,
+        Err(e) => {
+            error!("{}", e);
+            process::exit(1);
+        }
     };
 
-    if let Some(_) = matches.subcommand_matches("verify") {
-        publish(root_path.clone(), templates_path.clone(), true, false).unwrap();
+    if matches.subcommand_matches("verify").is_some() {
+        if let Err(e) = publish(&config, &assets, true, false) {
+            error!("{}", e);
+            process::exit(1);
+        }
+    }
+
+    if let Some(sbc) = matches.subcommand_matches("clean") {
+        let dryrun = 1 == sbc.occurrences_of("DRYRUN");
+        if let Err(e) = clean(&config, &assets, dryrun) {
+            error!("{}", e);
+            process::exit(1);
+        }
+    }
+
+    if matches.subcommand_matches("lint").is_some() {
+        match lint::lint(config.paths.source.clone(), config.paths.templates.clone(), &config.lint.schema) {
+            Ok(0) => {}
+            Ok(_) => process::exit(1),
+            Err(e) => {
+                error!("{}", e);
+                process::exit(1);
+            }
+        }
     }
 
     if let Some(sbc) = matches.subcommand_matches("publish") {
         let force = 1 == sbc.occurrences_of("FORCE");
-        publish(root_path.clone(), templates_path.clone(), false, force).unwrap();
+        if let Err(e) = publish(&config, &assets, false, force) {
+            error!("{}", e);
+            process::exit(1);
+        }
     }
 
     if 1 == matches.occurrences_of("DEV") {
-        start_dev_server(root_path, templates_path).await;
+        start_dev_server(config, assets).await;
     }
 }
 
-/// Republishes the source files. If dryrun is set to true, this function will only print out to the users the file which will be changed with a full publish.
+/// Hashes of the inputs of a page, its metadata and content, and the reason why it needs to be published again, if any
+type PageState = (Inputs, Result<MarkdownPage, RenderError>, Option<Outdated>);
+
+/// A page to publish along with the hashes of its inputs
+type PendingPage = (PathBuf, Inputs, Result<MarkdownPage, RenderError>);
+
+/// Republishes the source files to the output directory. If dryrun is set to true, this function will only print out to the users the file which will be changed with a full publish.
+/// A page is outdated when the hash of its source, of its templates or of the site settings differs from the build manifest.
 /// If force is set to true (ignored in case of dryrun) this function will republish all the files.
+/// Static assets are copied to the output directory when their content changed and the output files which source was removed are deleted.
 fn publish(
-    root_path: PathBuf,
-    templates_path: PathBuf,
+    config: &Config,
+    assets: &AssetFilter,
     dryrun: bool,
     force: bool,
 ) -> Result<(), Box<dyn Error>> {
-    let templates_ts = match template::last_changed(&templates_path) {
+    config.check_output()?;
+    let root_path = &config.paths.source;
+    let output_path = &config.paths.output;
+    let settings = config.render_settings();
+
+    let templates = match template::fingerprints(&settings.templates_location) {
         Ok(t) => t,
         Err(e) => bail!(e),
     };
+    let settings_hash = settings.fingerprint();
+    let mut manifest = Manifest::load(config);
 
-    walk_dir(root_path, "md", true, &move |p: &Path| {
-        let markdown = p.metadata().unwrap();
-        let html_path = p.with_extension("html");
-        let mut publish = false;
-        let mut reason: String = String::from("");
-
-        if html_path.exists() && force == false {
-            let html = html_path.metadata().unwrap();
-
-            let mdchange = markdown.modified().unwrap();
-            let htchange = html.modified().unwrap();
-
-            if mdchange > htchange {
-                publish = true;
-                reason = format!("{:#?} was changed and requires re-publishing", p);
-            } else {
-                // Check if the template changed
-                let md = metadata::MarkdownMetaData::from_file(p);
-                if let Some(metadata) = md {
-                    if let Some(tplchange) = templates_ts.get(&metadata.layout) {
-                        publish = *tplchange > htchange;
-                        reason = format!(
-                            "{:#?} requires re-publishing due to template change [{}]",
-                            p, metadata.layout
-                        );
-                    }
+    let sources = list_files(root_path, Some("md"));
+
+    let started = Instant::now();
+    // The pages are read and their metadata extracted once, the problems found are only logged afterwards to keep them in the order of the pages
+    let checked: Vec<(PathBuf, Result<PageState, RenderError>)> = sources
+        .into_par_iter()
+        .map(|p| {
+            let html_path = mirror_path(&p, root_path, output_path).with_extension("html");
+            let state = read_markdown(&p).map(|content| {
+                let page = parse_markdown(&p, &content);
+                let layout = page.as_ref().ok().map(|page| &page.metadata.layout);
+                let inputs = manifest::page_inputs(&content, layout, &settings_hash, &templates);
+                let outdated = manifest.outdated(&html_path, &inputs);
+                (inputs, page, outdated)
+            });
+            (p, state)
+        })
+        .collect();
+
+    let mut pages: Vec<PendingPage> = vec![];
+    let mut skipped = 0;
+    let mut failed = 0;
+    for (p, state) in checked {
+        match state {
+            Err(e) => {
+                error!("Unable to read {:#?} this file will be skipped:{}", p, e);
+                failed += 1;
+            }
+            Ok((_, _, Some(reason))) if dryrun => info!("{:#?} {}", p, reason),
+            Ok((inputs, page, outdated)) if force || outdated.is_some() => pages.push((p, inputs, page)),
+            Ok(_) => skipped += 1,
+        }
+    }
+
+    if !dryrun {
+        let engine = match template::load_templates(Some(settings.templates_location.clone())) {
+            Ok(e) => e,
+            Err(e) => bail!(e.to_string()),
+        };
+        let rendered = render_pages(&pages, root_path, output_path, &settings, &engine);
+        let mut published = 0;
+        for ((p, inputs, page), result) in pages.into_iter().zip(rendered) {
+            for warning in page.iter().flat_map(|page| &page.metadata.warnings) {
+                warn!("{:#?}: {}", p, warning);
+            }
+            match result {
+                Ok(html) => {
+                    info!("Published {:#?}", p);
+                    manifest.record(&html, &p, inputs);
+                    published += 1;
+                }
+                Err(e) => {
+                    error!("Something went wrong while publishing {:#?} this file will be skipped:{}", p, e);
+                    failed += 1;
                 }
             }
+        }
+        info!(
+            "{} page(s) published, {} up to date, {} failed in {:.2?}",
+            published,
+            skipped,
+            failed,
+            started.elapsed()
+        );
+    }
+
+    if config.features.copy_assets {
+        copy_assets(root_path, output_path, assets, &mut manifest, dryrun);
+    }
+
+    if config.features.remove_stale {
+        let selected = if config.features.copy_assets { Some(assets) } else { None };
+        remove_orphans(&mut manifest, selected, dryrun);
+    }
+
+    if !dryrun {
+        if let Err(e) = manifest.save() {
+            error!("Unable to save the build manifest: {}", e);
+        }
+    }
+
+    Ok(())
+}
+
+/// Renders the provided markdown pages concurrently to the output directory, the results are returned in the same order as the pages
+/// so that they are logged in a predictable order
+fn render_pages(
+    pages: &[PendingPage],
+    root_path: &Path,
+    output_path: &Path,
+    settings: &RenderSettings,
+    engine: &Tera,
+) -> Vec<Result<PathBuf, Box<dyn Error + Send + Sync>>> {
+    pages
+        .par_iter()
+        .map(|(p, _, page)| {
+            let page = page.as_ref().map_err(|e| e.clone())?;
+            let document = render_page(p, page, settings, engine)?;
+            write_html(p, Some(mirror_path(p, root_path, output_path)), &document)
+        })
+        .collect()
+}
+
+/// Removes the output files listed in the build manifest which source was deleted or renamed, or which are now ignored.
+/// If dryrun is set to true, the files are only listed.
+fn clean(config: &Config, assets: &AssetFilter, dryrun: bool) -> Result<(), Box<dyn Error>> {
+    config.check_output()?;
+    let mut manifest = Manifest::load(config);
+    let selected = if config.features.copy_assets { Some(assets) } else { None };
+    let removed = remove_orphans(&mut manifest, selected, dryrun);
+    if dryrun {
+        info!("{} file(s) would be removed", removed);
+    } else {
+        manifest.save()?;
+        info!("{} file(s) removed", removed);
+    }
+    Ok(())
+}
+
+/// Removes the output files which source does not exist anymore or which are not selected as assets anymore and returns how many were found.
+/// Copied assets are only considered if __assets__ is set. If dryrun is set to true, the files are only listed.
+fn remove_orphans(manifest: &mut Manifest, assets: Option<&AssetFilter>, dryrun: bool) -> usize {
+    let orphans = manifest.orphans(assets);
+    for orphan in &orphans {
+        if dryrun {
+            info!("{:#?} is not published anymore and requires removal", orphan);
+        } else {
+            info!("Removing {:#?}", orphan);
+            match fs::remove_file(orphan) {
+                Ok(_) => manifest.forget(orphan),
+                Err(e) => error!("Unable to remove {:#?}: {}", orphan, e),
+            }
+        }
+    }
+    orphans.len()
+}
+
+/// Copies the static assets of the source directory which changed to the output directory
+fn copy_assets(root_path: &Path, output_path: &Path, assets: &AssetFilter, manifest: &mut Manifest, dryrun: bool) {
+    let manifest = RefCell::new(manifest);
+    walk_dir(root_path.to_path_buf(), None, true, &|p: &Path| {
+        let relative = match p.strip_prefix(root_path) {
+            Ok(r) => r,
+            Err(_) => return,
+        };
+        if p.starts_with(output_path) || !assets.is_asset(relative) {
+            return;
+        }
+
+        let destination = output_path.join(relative);
+        if assets::is_rendered(&destination, root_path, output_path) {
+            warn!("{:#?} will not be copied as it would overwrite the page rendered from {:#?}", p, p.with_extension("md"));
+            return;
+        }
+
+        if dryrun {
+            if let Ok(true) = assets::asset_changed(p, &destination) {
+                info!("{:#?} was changed and requires copying", p);
+            }
         } else {
-            publish = true;
-            reason = format!("{:#?} has not been published yet", p);
-        }
-
-        if !dryrun {
-            if publish {
-                info!("Publishing {:#?}", p);
-                if let Err(e) =
-                    convert::markdown_to_html(p.to_owned(), None, templates_path.to_owned())
-                {
-                    error!(
-                        "Something went wrong while publishing {:#?} this file will be skipped:{}",
-                        p, e
-                    );
-                };
+            match assets::copy_asset(p, &destination) {
+                Ok(copied) => {
+                    if copied {
+                        info!("Copying {:#?}", p);
+                    }
+                    match manifest::asset_inputs(p) {
+                        Ok(inputs) => manifest.borrow_mut().record(&destination, p, inputs),
+                        Err(e) => warn!("Unable to track {:#?} in the build manifest: {}", p, e),
+                    }
+                }
+                Err(e) => error!("Something went wrong while copying {:#?} this file will be skipped:{}", p, e),
             }
-        } else if publish {
-            info!("{}", reason);
         }
     });
-
-    Ok(())
 }
 
-/// Starts the development server and monitors file changes
-async fn start_dev_server(root_path: PathBuf, templates_path: PathBuf) {
-    let (sender, mut receiver): (Sender<String>, Receiver<String>) =
+/// Starts the development server and monitors file changes.
+/// Pages are rendered in memory when requested, nothing is written to the output directory.
+async fn start_dev_server(config: Config, assets: AssetFilter) {
+    let preview = Arc::new(Preview::new(&config, assets.clone()));
+
+    let (sender, mut receiver): (Sender<Vec<Change>>, Receiver<Vec<Change>>) =
         tokio::sync::mpsc::channel(100);
 
-    let root_watcher = watcher::make_fs_watcher(root_path.clone(), sender.clone(), true, 1000);
-    let templates_watcher = watcher::make_fs_watcher(templates_path.clone(), sender, true, 1000);
+    let fs_watcher = watcher::make_fs_watcher(
+        vec![config.paths.source.clone(), config.paths.templates.clone()],
+        sender,
+        true,
+        config.watcher.mode(),
+        config.watcher.debounce,
+    );
 
     let server = DevServer::new();
-    let server_task = server.serve(root_path.clone(), 4200, true, None);
+    let serving = server.serve(
+        preview.clone(),
+        config.server.host.clone(),
+        config.server.port,
+        config.features.open_browser,
+        None,
+    );
+    let server_task = async {
+        if let Err(e) = serving.await {
+            error!("{}", e);
+            process::exit(1);
+        }
+    };
     let clients = server.clients();
 
     let consumer = tokio::task::spawn(async move {
-        loop {
-            let message = receiver.recv().await;
-            if let Some(text) = message {
-                info!("File {} changed", &text);
-                let file_path = Path::new(&text);
-
-                let extension = match file_path.extension() {
-                    Some(e) => e,
-                    None => OsStr::new(""),
-                };
-
-                if file_path.starts_with(templates_path.to_path_buf()) {
-                    if extension == "html" {
-                        let mut matches: Vec<PathBuf> = vec![];
-                        let layout = convert::metadata::Layout::from(
-                            file_path.file_name().unwrap().to_str().unwrap(),
-                        );
-                        convert::template::find_usage(&root_path, &layout, &mut matches);
-                        for file in matches {
-                            let filepath = file.clone();
-                            if let Err(e) = markdown_to_html(file, None, templates_path.to_path_buf()){
-                                error!("An error occured while refreshing {:#?} after its layout {} changed: {}", filepath, &layout, e);    
-                            }
-                        }
-                        devserver::send_message(
-                            &clients,
-                            ClientMessage::Reload,
-                        )
-                        .await;
+        while let Some(changes) = receiver.recv().await {
+            for (recipients, message) in refresh(&changes, &config, &assets, &preview) {
+                devserver::send_message_to(&clients, &recipients, message).await;
+            }
+        }
+    });
+
+    let (_, _, _) = join!(fs_watcher, consumer, server_task);
+}
+
+/// Drops the pages affected by a batch of changed files from memory, they will be rendered again when requested.
+/// Returns the messages the connected browsers should receive along with the browsers they are meant for: the outcome of the rendering
+/// if pages were rendered again, the errors of the pages which failed to render and the update to apply if anything they may display changed.
+fn refresh(changes: &[Change], config: &Config, assets: &AssetFilter, preview: &Preview) -> Vec<(Recipients, ClientMessage)> {
+    let root_path = &config.paths.source;
+    let templates_path = &config.paths.templates;
+
+    let mut updated: Vec<(&Path, bool)> = vec![];
+    for change in changes {
+        info!("{}", change);
+        match change {
+            Change::Created(p) | Change::Modified(p) => updated.push((p, false)),
+            Change::Deleted(p) => updated.push((p, true)),
+            Change::Renamed(from, to) => {
+                updated.push((from, true));
+                updated.push((to, false));
+            }
+        }
+    }
+
+    let mut pages: Vec<String> = vec![];
+    let mut removed_pages: Vec<String> = vec![];
+    let mut sources: Vec<PathBuf> = vec![];
+    let mut stylesheets: Vec<String> = vec![];
+    let mut reload = false;
+
+    for (file_path, removed) in updated {
+        let extension = match file_path.extension() {
+            Some(e) => e,
+            None => OsStr::new(""),
+        };
+
+        if file_path.starts_with(templates_path) {
+            if extension == "html" {
+                let mut matches: Vec<PathBuf> = vec![];
+                preview.reload_templates();
+                convert::template::find_usage(root_path, templates_path, file_path, &mut matches);
+                for file in matches {
+                    preview.invalidate(&file);
+                    if !sources.contains(&file) {
+                        sources.push(file);
                     }
-                    continue;
-                } else if extension == "md" {
-                    if let Ok(html) = markdown_to_html(
-                        file_path.to_path_buf(),
-                        None,
-                        templates_path.to_path_buf(),
-                    ) {
-                        if let Ok(p) = html.strip_prefix(&root_path) {
-                            let str = p.to_str().unwrap();
-                            devserver::send_message(
-                                &clients,
-                                ClientMessage::Navigate(str.replace(MAIN_SEPARATOR, "/")),
-                            )
-                            .await;
-                        }
+                }
+                reload = true;
+            }
+        } else if extension == "md" {
+            preview.invalidate(file_path);
+            if let Some(url) = preview.url(file_path) {
+                if removed {
+                    removed_pages.push(url);
+                } else if !pages.contains(&url) {
+                    pages.push(url);
+                    sources.push(file_path.to_path_buf());
+                }
+            }
+        } else if let Ok(relative) = file_path.strip_prefix(root_path) {
+            if !assets.is_asset(relative) {
+                continue;
+            }
+            // Updated stylesheets are swapped in place, keeping the state of the pages displaying them
+            match preview.asset_url(file_path) {
+                Some(url) if extension == "css" && !removed => {
+                    if !stylesheets.contains(&url) {
+                        stylesheets.push(url);
                     }
                 }
+                _ => reload = true,
             }
         }
-    });
+    }
+
+    // Rendering the pages right away reports the errors without waiting for the browsers to request them
+    let (status, errors) = preview.render_all(&sources);
+
+    let mut messages = vec![];
+    if !sources.is_empty() {
+        messages.push((Recipients::All, ClientMessage::BuildStatus(status)));
+    }
+    if !removed_pages.is_empty() {
+        messages.push((Recipients::All, ClientMessage::Removed(removed_pages)));
+        return messages;
+    }
 
-    let (_, _, _, _) = join!(templates_watcher, root_watcher, consumer, server_task);
+    // Errors are only displayed by the browsers showing the page which failed to render
+    let (errors, mut failed) = preview.error_messages(&errors);
+    messages.extend(errors);
+
+    // A single edited page is brought into view, anything broader refreshes whatever the browsers display
+    let update = match (reload, pages.as_slice()) {
+        (false, [url]) => {
+            // The browsers displaying the page update it in place, the others navigate to it
+            if failed.is_empty() {
+                if let Ok(page) = preview.render(&sources[0]) {
+                    let patch = ClientMessage::Patch {
+                        url: url.clone(),
+                        html: devserver::with_uplink(&page),
+                    };
+                    messages.push((Recipients::Page(url.clone()), patch));
+                    failed.push(url.clone());
+                }
+            }
+            Some(ClientMessage::Navigate(url.clone()))
+        }
+        (false, []) if !stylesheets.is_empty() => Some(ClientMessage::CssUpdate(stylesheets)),
+        (false, []) => None,
+        _ => Some(ClientMessage::Reload),
+    };
+    messages.extend(update.map(|m| (Recipients::Except(failed), m)));
+    messages
 }
@@ -0,0 +1,258 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    error::Error,
+    fmt::Display,
+    fs, io,
+    path::{Path, PathBuf, MAIN_SEPARATOR},
+};
+
+use log::warn;
+use serde::{Deserialize, Serialize};
+
+use crate::assets::AssetFilter;
+use crate::config::Config;
+use crate::convert::metadata::Layout;
+use crate::filesystem::{hash, hash_file};
+
+/// Name of the file, in the state directory, listing the files written by micro before each output directory got its own manifest
+const LEGACY_MANIFEST_FILE: &str = "manifest.json";
+
+/// Name of the input holding the hash of the source file
+const SOURCE_INPUT: &str = "source";
+/// Name of the input holding the hash of the settings shared by all the pages (site data and markdown extensions)
+const SETTINGS_INPUT: &str = "settings";
+/// Prefix of the inputs holding the hash of a template, followed by the template name
+const TEMPLATE_INPUT: &str = "template:";
+
+/// Hashes of everything an output file is generated from, keyed by input name
+pub type Inputs = BTreeMap<String, String>;
+
+/// An output file written by micro
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct Entry {
+    /// Source file relative to the source directory
+    pub source: String,
+    pub inputs: Inputs,
+}
+
+impl Entry {
+    /// Returns true if the output file is a page rendered from a markdown file rather than a copied asset
+    pub fn is_page(&self) -> bool {
+        self.source.ends_with(".md")
+    }
+}
+
+/// Reason why an output file has to be generated again
+#[derive(Debug, Clone, PartialEq)]
+pub enum Outdated {
+    /// The file was never written or was removed since
+    Missing,
+    Source,
+    Settings,
+    /// One of the templates the page is rendered with changed, was added or removed
+    Template(String),
+}
+
+impl Display for Outdated {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Outdated::Missing => write!(f, "has not been published yet"),
+            Outdated::Source => write!(f, "was changed and requires re-publishing"),
+            Outdated::Settings => write!(f, "requires re-publishing due to site settings change"),
+            Outdated::Template(name) => write!(f, "requires re-publishing due to template change [{}]", name),
+        }
+    }
+}
+
+/// Build manifest listing every file micro wrote to the output directory along with the source file it was generated from
+/// and the hashes of all its inputs, so that a file is only generated again when one of them actually changed.
+/// Files of the output directory which are not listed were not written by micro and are never removed.
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct Manifest {
+    /// Source directory the files were generated from
+    #[serde(default)]
+    root_path: PathBuf,
+    /// Output directory the files were written to, the file paths are relative to it
+    #[serde(default)]
+    output_path: PathBuf,
+    /// Output files keyed by their path relative to the output directory
+    files: BTreeMap<String, Entry>,
+    #[serde(skip)]
+    path: PathBuf,
+    /// Set when the manifest found was written for other directories, it is then left untouched on the disk
+    #[serde(skip)]
+    discarded: bool,
+}
+
+impl Manifest {
+    /// Loads the manifest of the output directory, an empty manifest is returned if micro never published anything to it.
+    /// Each output directory has its own manifest, so that publishing elsewhere does not lose track of the files written before.
+    /// The manifest is ignored if it was written for other source or output directories, as its entries would designate unrelated files.
+    pub fn load(config: &Config) -> Manifest {
+        let path = config.state_path().join(manifest_name(&config.paths.output));
+        let mut manifest = match Manifest::read(&path) {
+            Some(m) => m,
+            None => Manifest::read(&config.state_path().join(LEGACY_MANIFEST_FILE))
+                .filter(|m| m.written_for(config))
+                .unwrap_or_default(),
+        };
+        if !manifest.files.is_empty() && !manifest.written_for(config) {
+            warn!(
+                "Ignoring the build manifest {:#?} written for {:#?} and {:#?}, every file will be published again and the manifest left as is",
+                path, manifest.root_path, manifest.output_path
+            );
+            manifest = Manifest {
+                discarded: true,
+                ..Manifest::default()
+            };
+        }
+        manifest.path = path;
+        manifest.root_path = config.paths.source.clone();
+        manifest.output_path = config.paths.output.clone();
+        manifest
+    }
+
+    /// Returns true if the manifest was written for the source and output directories of __config__
+    fn written_for(&self, config: &Config) -> bool {
+        same_directory(&self.root_path, &config.paths.source) && same_directory(&self.output_path, &config.paths.output)
+    }
+
+    /// Reads the manifest stored at __path__, returns None if there is none or if it is invalid
+    fn read(path: &Path) -> Option<Manifest> {
+        let content = fs::read_to_string(path).ok()?;
+        serde_json::from_str(&content)
+            .map_err(|e| warn!("Ignoring the invalid build manifest {:#?} : {}", path, e))
+            .ok()
+    }
+
+    /// Writes the manifest to the state directory, unless the one found was written for other directories
+    pub fn save(&self) -> Result<(), Box<dyn Error>> {
+        if self.discarded {
+            return Ok(());
+        }
+        if let Some(parent) = self.path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        fs::write(&self.path, serde_json::to_string_pretty(self)?)?;
+        Ok(())
+    }
+
+    /// Records that __output__ was written from __source__ and the provided inputs
+    pub fn record(&mut self, output: &Path, source: &Path, inputs: Inputs) {
+        match (relative(output, &self.output_path), relative(source, &self.root_path)) {
+            (Some(o), Some(source)) => {
+                self.files.insert(o, Entry { source, inputs });
+            }
+            _ => warn!("{:#?} is not located in the output directory and will not be tracked", output),
+        }
+    }
+
+    /// Removes __output__ from the manifest, usually after it was deleted
+    pub fn forget(&mut self, output: &Path) {
+        if let Some(o) = relative(output, &self.output_path) {
+            self.files.remove(&o);
+        }
+    }
+
+    /// Compares the provided inputs to the ones __output__ was last written from, returns None if it is up to date
+    pub fn outdated(&self, output: &Path, inputs: &Inputs) -> Option<Outdated> {
+        let entry = match relative(output, &self.output_path).and_then(|o| self.files.get(&o)) {
+            Some(e) if output.is_file() => e,
+            _ => return Some(Outdated::Missing),
+        };
+        if &entry.inputs == inputs {
+            return None;
+        }
+
+        let differs = |name: &str| entry.inputs.get(name) != inputs.get(name);
+        if differs(SOURCE_INPUT) {
+            return Some(Outdated::Source);
+        }
+        if differs(SETTINGS_INPUT) {
+            return Some(Outdated::Settings);
+        }
+        let template = entry
+            .inputs
+            .keys()
+            .chain(inputs.keys())
+            .find(|name| differs(name))
+            .map(|name| name.trim_start_matches(TEMPLATE_INPUT).to_string());
+        Some(Outdated::Template(template.unwrap_or_default()))
+    }
+
+    /// Lists the output files written by micro which source does not exist anymore, along with the copied assets which are not selected by __assets__ anymore.
+    /// Copied assets are left out if __assets__ is None, so that turning assets copying off does not remove the ones copied before.
+    /// Entries which output file was removed by other means are dropped from the manifest.
+    pub fn orphans(&mut self, assets: Option<&AssetFilter>) -> Vec<PathBuf> {
+        let output_path = self.output_path.clone();
+        self.files.retain(|o, _| output_path.join(o).is_file());
+        self.files
+            .iter()
+            .filter(|(_, e)| {
+                if e.is_page() {
+                    return !self.root_path.join(&e.source).is_file();
+                }
+                match assets {
+                    Some(filter) => !self.root_path.join(&e.source).is_file() || !filter.is_asset(Path::new(&e.source)),
+                    None => false,
+                }
+            })
+            .map(|(o, _)| output_path.join(o))
+            .collect()
+    }
+}
+
+/// Computes the inputs of the page rendered from the markdown __content__: the content itself, the shared __settings__ hash
+/// and the templates of its __layout__, looked up in __templates__ (see template::fingerprints)
+pub fn page_inputs(content: &str, layout: Option<&Layout>, settings: &str, templates: &HashMap<Layout, BTreeMap<String, String>>) -> Inputs {
+    let mut inputs = Inputs::new();
+    inputs.insert(SOURCE_INPUT.to_string(), hash(content.as_bytes()));
+    inputs.insert(SETTINGS_INPUT.to_string(), settings.to_string());
+    if let Some(hashes) = layout.and_then(|l| templates.get(l)) {
+        for (name, hash) in hashes {
+            inputs.insert(format!("{}{}", TEMPLATE_INPUT, name), hash.clone());
+        }
+    }
+    inputs
+}
+
+/// Computes the inputs of a static asset copied from __source__
+pub fn asset_inputs(source: &Path) -> io::Result<Inputs> {
+    let mut inputs = Inputs::new();
+    inputs.insert(SOURCE_INPUT.to_string(), hash_file(source)?);
+    Ok(inputs)
+}
+
+/// Returns the name of the manifest of the provided output directory, derived from its canonical path.
+/// An output directory which does not exist yet is located through its parent, so that the name does not change once it is created.
+fn manifest_name(output: &Path) -> String {
+    let canonical = fs::canonicalize(output).unwrap_or_else(|_| {
+        let parent = match output.parent() {
+            Some(p) if !p.as_os_str().is_empty() => p,
+            _ => Path::new("."),
+        };
+        match (fs::canonicalize(parent), output.file_name()) {
+            (Ok(p), Some(name)) => p.join(name),
+            _ => output.to_path_buf(),
+        }
+    });
+    format!("manifest-{}.json", &hash(canonical.to_string_lossy().as_bytes())[..16])
+}
+
+/// Returns true if both paths designate the same directory
+fn same_directory(a: &Path, b: &Path) -> bool {
+    if a == b {
+        return true;
+    }
+    match (fs::canonicalize(a), fs::canonicalize(b)) {
+        (Ok(a), Ok(b)) => a == b,
+        _ => false,
+    }
+}
+
+/// Returns the path of __path__ relative to __base__ using forward slashes, so that manifests can be shared across platforms
+fn relative(path: &Path, base: &Path) -> Option<String> {
+    path.strip_prefix(base)
+        .ok()
+        .map(|p| p.to_string_lossy().replace(MAIN_SEPARATOR, "/"))
+}
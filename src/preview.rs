@@ -0,0 +1,287 @@
+use std::{
+    collections::HashMap,
+    error::Error,
+    path::{Component, Path, PathBuf, MAIN_SEPARATOR},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, RwLock,
+    },
+    time::Instant,
+};
+
+use log::{debug, error, info};
+use percent_encoding::percent_decode_str;
+use rayon::prelude::*;
+use tera::Tera;
+
+use crate::assets::AssetFilter;
+use crate::config::Config;
+use crate::convert::{render_markdown, template, RenderError, RenderSettings};
+use crate::devserver::{BuildStatus, ClientMessage, ErrorDetails, Recipients};
+use crate::filesystem::list_files;
+
+/// A source file served by the development server
+#[derive(Debug, Clone, PartialEq)]
+pub enum Resource {
+    /// A markdown file, served as the html page it renders to
+    Page(PathBuf),
+    /// A static asset, served as is
+    Asset(PathBuf),
+    /// A directory requested without a trailing slash, redirected to the provided url so that the relative links of its index page resolve against it
+    Redirect(String),
+}
+
+/// Renders the pages served by the development server on request, straight from the source directory.
+/// Rendered pages are kept in memory until their source or one of their templates changes, so that previewing never writes to the disk.
+pub struct Preview {
+    root_path: PathBuf,
+    settings: RenderSettings,
+    assets: AssetFilter,
+    /// Templates engine shared by all the pages, loaded again after a template changed
+    engine: RwLock<Option<Arc<Tera>>>,
+    cache: RwLock<HashMap<PathBuf, String>>,
+    /// Incremented whenever pages are dropped from memory, a page rendered meanwhile may be outdated and is not kept
+    generation: AtomicU64,
+}
+
+impl Preview {
+    pub fn new(config: &Config, assets: AssetFilter) -> Preview {
+        Preview {
+            root_path: config.paths.source.clone(),
+            settings: config.render_settings(),
+            assets,
+            engine: RwLock::new(None),
+            cache: RwLock::new(HashMap::new()),
+            generation: AtomicU64::new(0),
+        }
+    }
+
+    /// Returns the directory the pages and assets are served from
+    pub fn root_path(&self) -> &Path {
+        &self.root_path
+    }
+
+    /// Resolves the path of a request (/article/article.html) to the source file it designates.
+    /// Directories resolve to their index page, or to a redirection if the request path does not end with a slash.
+    /// Hidden or ignored files and paths leaving the source directory resolve to nothing.
+    pub fn resolve(&self, request: &str) -> Option<Resource> {
+        let decoded = percent_decode_str(request).decode_utf8().ok()?;
+        let mut relative = PathBuf::new();
+        for part in decoded.split('/').filter(|p| !p.is_empty()) {
+            let part = Path::new(part);
+            if part.components().any(|c| !matches!(c, Component::Normal(_))) {
+                return None;
+            }
+            relative.push(part);
+        }
+        if decoded.ends_with('/') {
+            relative.push("index.html");
+        } else if self.root_path.join(&relative).is_dir() {
+            // Hidden or ignored directories are not redirected, which would reveal their existence
+            return self.assets.is_asset(&relative).then(|| Resource::Redirect(format!("{}/", request)));
+        }
+
+        let source = self.root_path.join(&relative);
+        if relative.extension().is_some_and(|e| e == "html") && source.with_extension("md").is_file() {
+            return Some(Resource::Page(source.with_extension("md")));
+        }
+        if source.is_file() && self.assets.is_asset(&relative) {
+            return Some(Resource::Asset(source));
+        }
+        None
+    }
+
+    /// Returns the html page rendered from the provided markdown file, rendering it unless it is already in memory
+    pub fn render(&self, source: &Path) -> Result<String, RenderError> {
+        if let Some(page) = self.cache.read().unwrap().get(source) {
+            debug!("Serving {:#?} from memory", source);
+            return Ok(page.clone());
+        }
+
+        info!("Rendering {:#?}", source);
+        let generation = self.generation.load(Ordering::SeqCst);
+        let engine = self.engine().map_err(|e| RenderError::with_cause(source, e.to_string(), e.as_ref()))?;
+        let page = render_markdown(source, &self.settings, &engine)?;
+
+        let mut cache = self.cache.write().unwrap();
+        if self.generation.load(Ordering::SeqCst) == generation {
+            cache.insert(source.to_path_buf(), page.clone());
+        }
+        Ok(page)
+    }
+
+    /// Returns the templates engine, loading the templates if they changed since the last rendering
+    fn engine(&self) -> Result<Arc<Tera>, Box<dyn Error + Send + Sync>> {
+        if let Some(engine) = self.engine.read().unwrap().as_ref() {
+            return Ok(engine.clone());
+        }
+
+        let mut engine = self.engine.write().unwrap();
+        if let Some(e) = engine.as_ref() {
+            return Ok(e.clone());
+        }
+        let loaded = Arc::new(template::load_templates(Some(self.settings.templates_location.clone()))?);
+        *engine = Some(loaded.clone());
+        Ok(loaded)
+    }
+
+    /// Drops the templates engine so that the templates are loaded again on the next rendering
+    pub fn reload_templates(&self) {
+        *self.engine.write().unwrap() = None;
+    }
+
+    /// Drops the page rendered from the provided markdown file, it will be rendered again on the next request
+    pub fn invalidate(&self, source: &Path) {
+        let mut cache = self.cache.write().unwrap();
+        cache.remove(source);
+        self.generation.fetch_add(1, Ordering::SeqCst);
+    }
+
+    /// Drops every page kept in memory and renders all the pages of the source directory again.
+    /// Returns the messages telling the browsers how the build went: the pages which failed to render display their error, the others reload.
+    pub fn rebuild(&self) -> Vec<(Recipients, ClientMessage)> {
+        self.reload_templates();
+        let mut cache = self.cache.write().unwrap();
+        cache.clear();
+        self.generation.fetch_add(1, Ordering::SeqCst);
+        drop(cache);
+
+        let (status, errors) = self.render_all(&list_files(&self.root_path, Some("md")));
+        let mut messages = vec![(Recipients::All, ClientMessage::BuildStatus(status))];
+        let (errors, failed) = self.error_messages(&errors);
+        messages.extend(errors);
+        messages.push((Recipients::Except(failed), ClientMessage::Reload));
+        messages
+    }
+
+    /// Renders the provided markdown files concurrently, logging the errors along with how long it took
+    pub fn render_all(&self, sources: &[PathBuf]) -> (BuildStatus, Vec<RenderError>) {
+        let started = Instant::now();
+        let errors: Vec<RenderError> = sources.par_iter().filter_map(|s| self.render(s).err()).collect();
+        for e in &errors {
+            error!("{}", e);
+        }
+        let status = BuildStatus {
+            pages: sources.len(),
+            failed: errors.len(),
+            duration_ms: started.elapsed().as_millis() as u64,
+        };
+        info!("{} page(s) rendered, {} failed in {:.2?}", status.pages, status.failed, started.elapsed());
+        (status, errors)
+    }
+
+    /// Returns the messages displaying the provided errors on the pages which failed to render, along with the urls of these pages
+    pub fn error_messages(&self, errors: &[RenderError]) -> (Vec<(Recipients, ClientMessage)>, Vec<String>) {
+        let mut messages = vec![];
+        let mut failed = vec![];
+        for e in errors {
+            if let Some(url) = self.url(&e.file) {
+                messages.push((Recipients::Page(url.clone()), ClientMessage::Error(self.error_details(e))));
+                failed.push(url);
+            }
+        }
+        (messages, failed)
+    }
+
+    /// Describes a rendering error to the browsers, locating the file relatively to the source directory
+    pub fn error_details(&self, error: &RenderError) -> ErrorDetails {
+        let file = error.file.strip_prefix(&self.root_path).unwrap_or(&error.file);
+        ErrorDetails {
+            file: file.to_string_lossy().replace(MAIN_SEPARATOR, "/"),
+            message: error.message.clone(),
+            line: error.location.map(|l| l.0),
+            column: error.location.map(|l| l.1),
+            template: error.template.clone(),
+            template_line: error.template_line,
+        }
+    }
+
+    /// Returns the url (relative to the site root) of the page rendered from the provided markdown file
+    pub fn url(&self, source: &Path) -> Option<String> {
+        self.asset_url(&source.with_extension("html"))
+    }
+
+    /// Returns the url (relative to the site root) the provided static asset is served at
+    pub fn asset_url(&self, source: &Path) -> Option<String> {
+        source
+            .strip_prefix(&self.root_path)
+            .ok()
+            .map(|p| p.to_string_lossy().replace(MAIN_SEPARATOR, "/"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    /// Creates a source directory (next to a file it must not give access to) and a preview serving it
+    fn preview(name: &str) -> Preview {
+        let base = std::env::temp_dir().join(format!("micro-{}-{}", name, std::process::id()));
+        let root_path = base.join("wwwroot");
+        for file in ["index.md", "article/article.md", "styles.css", ".env", ".git/config", "drafts/notes.css"] {
+            let path = root_path.join(file);
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(path, "").unwrap();
+        }
+        fs::write(base.join("secret.css"), "").unwrap();
+
+        Preview {
+            root_path,
+            settings: RenderSettings {
+                templates_location: PathBuf::from("templates"),
+                markdown_options: pulldown_cmark::Options::empty(),
+                site: tera::Value::Null,
+            },
+            assets: AssetFilter::new(&[String::from("drafts/*")]).unwrap(),
+            engine: RwLock::new(None),
+            cache: RwLock::new(HashMap::new()),
+            generation: AtomicU64::new(0),
+        }
+    }
+
+    #[test]
+    fn pages_and_assets_are_resolved() {
+        let preview = preview("resolve");
+        let root = preview.root_path().to_path_buf();
+        assert_eq!(preview.resolve("/"), Some(Resource::Page(root.join("index.md"))));
+        assert_eq!(preview.resolve("/index.html"), Some(Resource::Page(root.join("index.md"))));
+        assert_eq!(preview.resolve("/article/article.html"), Some(Resource::Page(root.join("article/article.md"))));
+        assert_eq!(preview.resolve("/styles.css"), Some(Resource::Asset(root.join("styles.css"))));
+        assert_eq!(preview.resolve("/missing.html"), None);
+        fs::remove_dir_all(root.parent().unwrap()).unwrap();
+    }
+
+    #[test]
+    fn paths_leaving_the_source_directory_resolve_to_nothing() {
+        let preview = preview("traversal");
+        assert!(preview.root_path().join("../secret.css").is_file());
+        assert_eq!(preview.resolve("/../secret.css"), None);
+        assert_eq!(preview.resolve("/article/../../secret.css"), None);
+        assert_eq!(preview.resolve("/%2e%2e/secret.css"), None);
+        assert_eq!(preview.resolve("/article/%2E%2E/%2e%2e/secret.css"), None);
+        assert_eq!(preview.resolve("/..%2fsecret.css"), None);
+        fs::remove_dir_all(preview.root_path().parent().unwrap()).unwrap();
+    }
+
+    #[test]
+    fn hidden_ignored_and_markdown_files_resolve_to_nothing() {
+        let preview = preview("hidden");
+        assert_eq!(preview.resolve("/.env"), None);
+        assert_eq!(preview.resolve("/.git/config"), None);
+        assert_eq!(preview.resolve("/%2egit/config"), None);
+        assert_eq!(preview.resolve("/drafts/notes.css"), None);
+        assert_eq!(preview.resolve("/index.md"), None);
+        assert_eq!(preview.resolve("/article/article.md"), None);
+        fs::remove_dir_all(preview.root_path().parent().unwrap()).unwrap();
+    }
+
+    #[test]
+    fn directories_without_a_trailing_slash_are_redirected() {
+        let preview = preview("redirect");
+        assert_eq!(preview.resolve("/article"), Some(Resource::Redirect(String::from("/article/"))));
+        assert_eq!(preview.resolve("/article/"), None);
+        assert_eq!(preview.resolve("/.git"), None);
+        fs::remove_dir_all(preview.root_path().parent().unwrap()).unwrap();
+    }
+}
@@ -1,34 +1,246 @@
-
-use futures::{
-    future::{BoxFuture},
-    FutureExt,
-};
 use log::{error, info, warn};
-use std::{path::PathBuf};
-use std::{time::SystemTime};
+use notify::{
+    event::{AccessKind, AccessMode},
+    Event, EventKind, RecommendedWatcher, RecursiveMode, Watcher,
+};
+use std::{
+    collections::HashMap,
+    fmt::Display,
+    io::ErrorKind,
+    path::{Path, PathBuf},
+    time::SystemTime,
+};
 use tokio::{
     fs,
-    sync::mpsc::{Sender},
+    sync::mpsc::{self, Receiver, Sender},
     task::JoinHandle,
-    time::{sleep, Duration},
+    time::{sleep, timeout, Duration, Instant},
 };
 
-/// Spawns a process which periodically scouts for file changes.
-/// Changes are passed to a handler function.
+/// How file changes are detected
+#[derive(Debug, Clone, Copy)]
+pub enum WatchMode {
+    /// Relies on the notifications of the operating system (inotify, FSEvents, ReadDirectoryChangesW)
+    Native,
+    /// Rescans the whole directory every given number of milliseconds
+    Poll(u64),
+}
+
+/// A change detected in a watched directory
+#[derive(Debug, Clone, PartialEq)]
+pub enum Change {
+    Created(PathBuf),
+    Modified(PathBuf),
+    Deleted(PathBuf),
+    /// A file was moved, from the first path to the second one
+    Renamed(PathBuf, PathBuf),
+}
+
+impl Display for Change {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Change::Created(path) => write!(f, "File {:#?} created", path),
+            Change::Modified(path) => write!(f, "File {:#?} changed", path),
+            Change::Deleted(path) => write!(f, "File {:#?} deleted", path),
+            Change::Renamed(from, to) => write!(f, "File {:#?} renamed to {:#?}", from, to),
+        }
+    }
+}
+
+/// Size and modification time of a file when it was last scanned
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct FileState {
+    len: u64,
+    modified: Option<SystemTime>,
+}
+
+/// State of all the files found in the watched directories during the previous scan.
+/// Changes are detected by rescanning a part of the directories and comparing the result to the snapshot.
+#[derive(Debug, Default)]
+struct Snapshot {
+    files: HashMap<PathBuf, FileState>,
+    recursive: bool,
+}
+
+impl Snapshot {
+    /// Takes a snapshot of the provided directories
+    fn new(paths: &[PathBuf], recursive: bool) -> Snapshot {
+        let mut snapshot = Snapshot {
+            files: HashMap::new(),
+            recursive,
+        };
+        for path in paths {
+            scan(path, recursive, &mut snapshot.files);
+        }
+        snapshot
+    }
+
+    /// Rescans the provided files or directories, updates the snapshot and returns the detected changes.
+    /// A file deleted while another one with the same size and modification time appeared is reported as renamed.
+    fn refresh(&mut self, scopes: &[PathBuf]) -> Vec<Change> {
+        let mut current = HashMap::new();
+        for scope in scopes {
+            scan(scope, self.recursive, &mut current);
+        }
+
+        let in_scope: Vec<PathBuf> = self
+            .files
+            .keys()
+            .filter(|p| scopes.iter().any(|s| p.starts_with(s)))
+            .cloned()
+            .collect();
+        let previous: HashMap<PathBuf, FileState> = in_scope
+            .into_iter()
+            .filter_map(|p| self.files.remove_entry(&p))
+            .collect();
+
+        let mut modified = vec![];
+        let mut created = vec![];
+        for (path, state) in &current {
+            match previous.get(path) {
+                Some(old) if old != state => modified.push(path.clone()),
+                Some(_) => {}
+                None => created.push((path.clone(), *state)),
+            }
+        }
+        let mut deleted: Vec<(PathBuf, FileState)> = previous
+            .into_iter()
+            .filter(|(p, _)| !current.contains_key(p))
+            .collect();
+        self.files.extend(current);
+
+        modified.sort();
+        created.sort_by(|a, b| a.0.cmp(&b.0));
+        deleted.sort_by(|a, b| a.0.cmp(&b.0));
+
+        let mut changes: Vec<Change> = modified.into_iter().map(Change::Modified).collect();
+        for (path, state) in deleted {
+            match created.iter().position(|(_, s)| state.modified.is_some() && *s == state) {
+                Some(index) => changes.push(Change::Renamed(path, created.remove(index).0)),
+                None => changes.push(Change::Deleted(path)),
+            }
+        }
+        changes.extend(created.into_iter().map(|(p, _)| Change::Created(p)));
+        changes
+    }
+}
+
+/// Records the state of __path__, or of all the files it contains if it is a directory, in __files__
+fn scan(path: &Path, recursive: bool, files: &mut HashMap<PathBuf, FileState>) {
+    let metadata = match std::fs::metadata(path) {
+        Ok(md) => md,
+        Err(e) => {
+            // Files vanishing while scanning are expected, they are reported as deleted
+            if e.kind() != ErrorKind::NotFound {
+                warn!("Unable to stat '{:#?}', this item will be skipped\n{}", path, e);
+            }
+            return;
+        }
+    };
+
+    if metadata.is_file() {
+        files.insert(
+            path.to_path_buf(),
+            FileState {
+                len: metadata.len(),
+                modified: metadata.modified().ok(),
+            },
+        );
+        return;
+    }
+
+    let directory = match std::fs::read_dir(path) {
+        Ok(d) => d,
+        Err(e) => {
+            if e.kind() != ErrorKind::NotFound {
+                warn!("Unable to read '{:#?}' content, the directory will be skipped : {}", path, e);
+            }
+            return;
+        }
+    };
+
+    for entry in directory.flatten() {
+        let item_path = entry.path();
+        if recursive || item_path.is_file() {
+            scan(&item_path, recursive, files);
+        }
+    }
+}
+
+/// Number of quiet periods after which pending changes are sent even though new changes keep being detected
+const MAX_BATCH_AGE: u32 = 10;
+
+/// Spawns a process which scouts for file changes in the provided directories.
+/// Changes are coalesced until no new change is detected for __quiet_period__ milliseconds and then passed as a single batch.
+/// A batch is sent at the latest after ten quiet periods, so that files which never stop changing do not hold back the others.
+/// If the operating system notifications are not available, the process falls back to polling every second.
 ///
 /// # Arguments
 ///
-/// * `path` - Path to the directory which should be looked monitored
-/// * `sender` - A channel to which modified paths will be sent to
+/// * `paths` - Paths to the directories which should be looked monitored
+/// * `sender` - A channel to which batches of changes will be sent to
 /// * `recursive` - If true, will recursively look for changes in sub directories
-/// * `poll_frequency` - Interval in milliseconds between file changes scans  
+/// * `mode` - Whether to rely on the operating system notifications or to poll for changes
+/// * `quiet_period` - Time in milliseconds without any change after which the pending changes are sent
 pub async fn make_fs_watcher(
+    paths: Vec<PathBuf>,
+    sender: Sender<Vec<Change>>,
+    recursive: bool,
+    mode: WatchMode,
+    quiet_period: u64,
+) -> JoinHandle<()> {
+    let (scope_sender, scope_receiver) = mpsc::channel(100);
+    let snapshot = Snapshot::new(&paths, recursive);
+    for path in paths {
+        // Dropping the handle detaches the watching task, which ends by itself once the batching task stops listening
+        drop(spawn_watcher(path, scope_sender.clone(), recursive, mode).await);
+    }
+    tokio::task::spawn(batch_changes(snapshot, scope_receiver, sender, Duration::from_millis(quiet_period)))
+}
+
+/// Collects the paths which may have changed from __receiver__ until none was received for __quiet_period__ (or the batch is too old),
+/// then rescans them and forwards the detected changes to __sender__ as a single batch
+async fn batch_changes(
+    mut snapshot: Snapshot,
+    mut receiver: Receiver<PathBuf>,
+    sender: Sender<Vec<Change>>,
+    quiet_period: Duration,
+) {
+    while let Some(first) = receiver.recv().await {
+        let mut scopes = vec![first];
+        let deadline = Instant::now() + quiet_period * MAX_BATCH_AGE;
+        while Instant::now() < deadline {
+            let wait = quiet_period.min(deadline.saturating_duration_since(Instant::now()));
+            match timeout(wait, receiver.recv()).await {
+                Ok(Some(path)) => scopes.push(path),
+                _ => break,
+            }
+        }
+
+        // Paths located in another rescanned directory would be reported twice
+        scopes.sort();
+        scopes.dedup();
+        let outer = scopes.clone();
+        scopes.retain(|s| !outer.iter().any(|o| o != s && s.starts_with(o)));
+
+        let changes = snapshot.refresh(&scopes);
+        if changes.is_empty() {
+            continue;
+        }
+        if let Err(e) = sender.send(changes).await {
+            error!("Unable to notifty the detected changes : {}", e);
+            return;
+        }
+    }
+}
+
+/// Spawns a process which sends the paths which may have changed to __sender__
+async fn spawn_watcher(
     path: PathBuf,
-    sender: Sender<String>,
+    sender: Sender<PathBuf>,
     recursive: bool,
-    poll_frequency: u64,
+    mode: WatchMode,
 ) -> JoinHandle<()> {
-    info!("Watching {:#?} for changes", path);
 
     let metadata = match fs::metadata(&path).await{
         Ok(m) => m,
@@ -41,66 +253,57 @@ pub async fn make_fs_watcher(
             path
         );
     }
-    let mut last_run = SystemTime::now();
+    let poll_frequency = match mode {
+        WatchMode::Poll(frequency) => frequency,
+        WatchMode::Native => match watch_native(&path, sender.clone(), recursive) {
+            Ok(watcher) => {
+                info!("Watching {:#?} for changes", path);
+                return tokio::task::spawn(async move {
+                    // The watcher stops as soon as it is dropped, keep it until nobody listens anymore
+                    let _watcher = watcher;
+                    sender.closed().await;
+                });
+            }
+            Err(e) => {
+                warn!("Unable to receive change notifications for {:#?}, falling back to polling: {}", path, e);
+                1000
+            }
+        },
+    };
+
+    info!("Watching {:#?} for changes every {}ms", path, poll_frequency);
 
-    let task = tokio::task::spawn(async move {
-        loop {
-            scan_changes(path.clone(), last_run, sender.clone(), recursive).await;
-            last_run = SystemTime::now();
-            sleep(Duration::from_millis(poll_frequency)).await; 
+    // The whole directory is only requested to be rescanned when a tick detects a change,
+    // polling faster than the quiet period would otherwise keep the changes from ever being sent
+    let mut snapshot = Snapshot::new(std::slice::from_ref(&path), recursive);
+    tokio::task::spawn(async move {
+        while !sender.is_closed() {
+            sleep(Duration::from_millis(poll_frequency)).await;
+            if !snapshot.refresh(std::slice::from_ref(&path)).is_empty() && sender.send(path.clone()).await.is_err() {
+                break;
+            }
         }
-    });
-    task
+    })
 }
 
-fn scan_changes(
-    path: PathBuf,
-    ref_time: std::time::SystemTime,
-    sender: Sender<String>,
-    recursive: bool,
-) -> BoxFuture<'static, ()> {
-    async move {
-        let directory = match std::fs::read_dir(&path) { 
-            Ok(d) => d,
-            Err(e) => {
-                warn!("Unable to read '{:#?}' content, the directory will be skipped : {}", &path, e);
-                return ();
+/// Subscribes to the operating system notifications for __path__, the paths reported by the notifications are sent to __sender__
+fn watch_native(path: &Path, sender: Sender<PathBuf>, recursive: bool) -> notify::Result<RecommendedWatcher> {
+    let mut watcher = notify::recommended_watcher(move |result: notify::Result<Event>| match result {
+        Ok(event) => {
+            // Opening or closing a file without writing to it does not change anything
+            if matches!(event.kind, EventKind::Access(kind) if kind != AccessKind::Close(AccessMode::Write)) {
+                return;
             }
-        };
-
-        for entry in directory {
-            let item_path = match entry {
-                Ok(p) => p.path(),
-                Err(e) => {
-                    warn!(
-                        "An error occured while iterating through '{:#?}', the faulty item will be skipped : {}",
-                        &path, e
-                    );
-                    continue;
+            for item_path in event.paths {
+                if let Err(e) = sender.blocking_send(item_path) {
+                    error!("Unable to notifty a detected change : {}", e);
                 }
-            };
-
-            let metadata = match std::fs::metadata(&item_path) {
-                Ok(md) => md,
-                Err(err) => {
-                    warn!("Unable to stat '{:#?}', this item will be skipped\n{}", &item_path, err);
-                    continue;
-                }
-            };
-
-            if metadata.is_file() {
-                if let Ok(time) = metadata.modified() {
-                    if time > ref_time {
-                        if let Err(e) = sender.send(String::from(item_path.to_str().unwrap())).await
-                        {
-                            error!("Unable to notifty a detected change to '{:#?}' : {}", &item_path, e);
-                        }
-                    }
-                }
-            } else if recursive {
-                scan_changes(item_path, ref_time, sender.clone(), recursive).await;
             }
         }
-    }
-    .boxed()
+        Err(e) => warn!("An error occured while watching for changes : {}", e),
+    })?;
+
+    let mode = if recursive { RecursiveMode::Recursive } else { RecursiveMode::NonRecursive };
+    watcher.watch(path, mode)?;
+    Ok(watcher)
 }